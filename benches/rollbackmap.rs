@@ -0,0 +1,296 @@
+use criterion::{
+    black_box, criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput,
+};
+use rollbackmap::bloom::BloomRollbackMap;
+use rollbackmap::indexed::IndexedRollbackMap;
+use rollbackmap::RollbackMap;
+use std::collections::BTreeMap;
+
+const KEYS: u32 = 1000;
+const DEPTHS: [u32; 4] = [1, 10, 100, 1000];
+
+// Map of KEYS keys spread over `depth` checkpoints, every one updating a part of them.
+fn layered_map(depth: u32) -> RollbackMap<u32, u32> {
+    let mut map = RollbackMap::new();
+    for key in 0..KEYS {
+        map.insert(key, key);
+    }
+    for layer in 0..depth {
+        map.checkpoint();
+        for key in (layer % 10..KEYS).step_by(10) {
+            map.insert(key, layer);
+        }
+    }
+    map
+}
+
+// Baseline keeping a copy of the whole map for every checkpoint.
+#[derive(Clone, Default)]
+struct CloningMap {
+    current: BTreeMap<u32, u32>,
+    checkpoints: Vec<BTreeMap<u32, u32>>,
+}
+
+// Same as layered_map, for the baseline.
+fn layered_baseline(depth: u32) -> CloningMap {
+    let mut map = CloningMap::default();
+    for key in 0..KEYS {
+        map.current.insert(key, key);
+    }
+    for layer in 0..depth {
+        map.checkpoint();
+        for key in (layer % 10..KEYS).step_by(10) {
+            map.current.insert(key, layer);
+        }
+    }
+    map
+}
+
+impl CloningMap {
+    fn checkpoint(&mut self) -> usize {
+        self.checkpoints.push(self.current.clone());
+        self.checkpoints.len() - 1
+    }
+
+    fn rollback(&mut self, checkpoint: usize) {
+        self.checkpoints.truncate(checkpoint + 1);
+        self.current = self.checkpoints[checkpoint].clone();
+    }
+}
+
+fn bench_get(c: &mut Criterion) {
+    let mut group = c.benchmark_group("get");
+    group.throughput(Throughput::Elements(u64::from(KEYS)));
+    for &depth in DEPTHS.iter() {
+        let map = layered_map(depth);
+        group.bench_with_input(BenchmarkId::new("hit", depth), &map, |b, map| {
+            b.iter(|| (0..KEYS).filter(|key| map.get(key).is_some()).count())
+        });
+        group.bench_with_input(BenchmarkId::new("miss", depth), &map, |b, map| {
+            b.iter(|| {
+                (KEYS..2 * KEYS)
+                    .filter(|key| map.get(key).is_some())
+                    .count()
+            })
+        });
+
+        let bloom = BloomRollbackMap::from_map(map.clone(), 10);
+        group.bench_with_input(BenchmarkId::new("bloom_miss", depth), &bloom, |b, map| {
+            b.iter(|| {
+                (KEYS..2 * KEYS)
+                    .filter(|key| map.get(key).is_some())
+                    .count()
+            })
+        });
+        let indexed = IndexedRollbackMap::from_map(map);
+        group.bench_with_input(
+            BenchmarkId::new("indexed_hit", depth),
+            &indexed,
+            |b, map| b.iter(|| (0..KEYS).filter(|key| map.get(key).is_some()).count()),
+        );
+
+        let baseline = layered_baseline(depth);
+        group.bench_with_input(
+            BenchmarkId::new("btreemap_clone", depth),
+            &baseline,
+            |b, map| {
+                b.iter(|| {
+                    (0..KEYS)
+                        .filter(|key| map.current.contains_key(key))
+                        .count()
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_insert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert");
+    group.throughput(Throughput::Elements(u64::from(KEYS)));
+    for &depth in DEPTHS.iter() {
+        let map = layered_map(depth);
+        group.bench_with_input(BenchmarkId::new("update", depth), &map, |b, map| {
+            b.iter_batched(
+                || map.clone(),
+                |mut map| {
+                    for key in 0..KEYS {
+                        map.insert(key, key);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        // The copies of the checkpoints do not take part in the updates
+        let baseline = layered_baseline(depth).current;
+        group.bench_with_input(
+            BenchmarkId::new("btreemap_clone", depth),
+            &baseline,
+            |b, map| {
+                b.iter_batched(
+                    || map.clone(),
+                    |mut map| {
+                        for key in 0..KEYS {
+                            map.insert(key, key);
+                        }
+                        map
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("remove");
+    group.throughput(Throughput::Elements(u64::from(KEYS)));
+    for &depth in DEPTHS.iter() {
+        let map = layered_map(depth);
+        group.bench_with_input(BenchmarkId::new("all", depth), &map, |b, map| {
+            b.iter_batched(
+                || map.clone(),
+                |mut map| {
+                    for key in 0..KEYS {
+                        map.remove(&key);
+                    }
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        // The copies of the checkpoints do not take part in the updates
+        let baseline = layered_baseline(depth).current;
+        group.bench_with_input(
+            BenchmarkId::new("btreemap_clone", depth),
+            &baseline,
+            |b, map| {
+                b.iter_batched(
+                    || map.clone(),
+                    |mut map| {
+                        for key in 0..KEYS {
+                            map.remove(&key);
+                        }
+                        map
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_rollback(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rollback");
+    for &size in [10u32, 100, 1000, 10_000].iter() {
+        group.throughput(Throughput::Elements(u64::from(size)));
+        let mut map = layered_map(10);
+        let checkpoint = map.checkpoint().unwrap();
+        for key in 0..size {
+            map.insert(key, key + 1);
+        }
+        map.checkpoint();
+        group.bench_with_input(BenchmarkId::new("layer_size", size), &map, |b, map| {
+            b.iter_batched(
+                || map.clone(),
+                |mut map| {
+                    assert!(map.rollback(checkpoint));
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        let mut baseline = layered_baseline(10);
+        let checkpoint = baseline.checkpoint();
+        for key in 0..size {
+            baseline.current.insert(key, key + 1);
+        }
+        baseline.checkpoint();
+        group.bench_with_input(
+            BenchmarkId::new("btreemap_clone", size),
+            &baseline,
+            |b, map| {
+                b.iter_batched(
+                    || map.clone(),
+                    |mut map| {
+                        map.rollback(checkpoint);
+                        map
+                    },
+                    BatchSize::LargeInput,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+fn bench_prune(c: &mut Criterion) {
+    let mut group = c.benchmark_group("prune");
+    for &depth in DEPTHS.iter() {
+        let map = layered_map(depth);
+        group.bench_with_input(BenchmarkId::new("depth", depth), &map, |b, map| {
+            b.iter_batched(
+                || map.clone(),
+                |mut map| {
+                    map.prune();
+                    map
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+// A checkpoint, a few updates, and a rollback to the checkpoint.
+fn bench_checkpoint_cycle(c: &mut Criterion) {
+    let mut group = c.benchmark_group("checkpoint_cycle");
+    for &size in [100u32, 1000, 10_000].iter() {
+        let mut map = RollbackMap::new();
+        let mut baseline = CloningMap::default();
+        for key in 0..size {
+            map.insert(key, key);
+            baseline.current.insert(key, key);
+        }
+        group.bench_with_input(BenchmarkId::new("rollbackmap", size), &size, |b, _| {
+            b.iter(|| {
+                let checkpoint = map.checkpoint().unwrap();
+                for key in 0..10 {
+                    map.insert(key, key + 1);
+                }
+                map.rollback(checkpoint);
+                map.prune();
+                black_box(map.len())
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("btreemap_clone", size), &size, |b, _| {
+            b.iter(|| {
+                let checkpoint = baseline.checkpoint();
+                for key in 0..10 {
+                    baseline.current.insert(key, key + 1);
+                }
+                baseline.rollback(checkpoint);
+                baseline.checkpoints.clear();
+                black_box(baseline.current.len())
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_get,
+    bench_insert,
+    bench_remove,
+    bench_rollback,
+    bench_prune,
+    bench_checkpoint_cycle
+);
+criterion_main!(benches);
@@ -0,0 +1,300 @@
+//! Batched updates of a [`RollbackMap`].
+//!
+//! A [`WriteBatch`] collects insertions and removals and applies them to the current
+//! layer at once. Its keys are sorted, so the older layers are searched in a single
+//! pass per layer for all the keys, instead of a lookup through all the layers per key.
+//! The map is changed only after all the keys are resolved, so a batch is applied
+//! as a whole.
+use crate::events::Event;
+use crate::rollbackmap::{split_last_mut, RollbackMap, VersionState};
+use core::iter::Peekable;
+use std::collections::BTreeMap;
+
+/// A set of insertions and removals applied to a [`RollbackMap`] at once
+/// by [`RollbackMap::write`].
+///
+/// A later change of a key replaces the earlier one.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::batch::WriteBatch;
+/// use rollbackmap::RollbackMap;
+///
+/// let mut map = RollbackMap::new();
+/// map.insert(1, "a");
+/// map.insert(2, "b");
+/// map.checkpoint();
+///
+/// let mut batch = WriteBatch::new();
+/// batch.insert(3, "c").remove(1).insert(2, "d");
+/// map.write(batch);
+///
+/// assert_eq!(map.get(&1), None);
+/// assert_eq!(map.get(&2), Some(&"d"));
+/// assert_eq!(map.get(&3), Some(&"c"));
+/// assert_eq!(map.len(), 2);
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteBatch<K, V> {
+    // The value to insert, or `None` to remove the key.
+    changes: BTreeMap<K, Option<V>>,
+}
+
+impl<K: Ord, V> Default for WriteBatch<K, V> {
+    /// Creates an empty `WriteBatch`.
+    fn default() -> Self {
+        WriteBatch::new()
+    }
+}
+
+impl<K: Ord, V> WriteBatch<K, V> {
+    /// Makes a new, empty `WriteBatch`.
+    pub fn new() -> Self {
+        WriteBatch {
+            changes: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the insertion of a key-value pair.
+    pub fn insert(&mut self, key: K, value: V) -> &mut Self {
+        self.changes.insert(key, Some(value));
+        self
+    }
+
+    /// Adds the removal of a key.
+    pub fn remove(&mut self, key: K) -> &mut Self {
+        self.changes.insert(key, None);
+        self
+    }
+
+    /// Returns the number of the changed keys.
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Returns `true` if the batch changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Removes all the changes.
+    pub fn clear(&mut self) {
+        self.changes.clear();
+    }
+}
+
+impl<K: Ord, V> Extend<(K, V)> for WriteBatch<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.insert(key, value);
+        }
+    }
+}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Applies the batch to the current version of the map.
+    ///
+    /// The changes are notified to the observers like the single insertions and
+    /// removals, in the order of the keys, once the whole batch is applied.
+    /// The changed keys are cloned for the observers, if there are any.
+    ///
+    /// Returns every key of the batch with its previous value, in the order of the keys.
+    /// Like [`RollbackMap::insert`], a previous value kept by a checkpoint is cloned.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::batch::WriteBatch;
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// let checkpoint = map.checkpoint().unwrap();
+    ///
+    /// let mut batch = WriteBatch::new();
+    /// batch.insert(1, "b").insert(2, "c");
+    /// assert_eq!(map.write(batch), vec![(1, Some("a")), (2, None)]);
+    ///
+    /// map.rollback(checkpoint);
+    /// assert_eq!(map.get(&1), Some(&"a"));
+    /// assert_eq!(map.get(&2), None);
+    /// ```
+    pub fn write(&mut self, batch: WriteBatch<K, V>) -> Vec<(K, Option<V>)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let found = {
+            let keys: Vec<&K> = batch.changes.keys().collect();
+            find_keys(&self.versions, &keys)
+        };
+        // The keys removed from the current layer whose values are kept by a checkpoint
+        // have to be hidden by tombstones.
+        let mut shadowed = vec![false; batch.changes.len()];
+        let (last, older) = self.versions.split_last().expect("the current version");
+        if !last.detached {
+            let (indices, keys): (Vec<usize>, Vec<&K>) = batch
+                .changes
+                .iter()
+                .enumerate()
+                .filter(|(_, (key, value))| value.is_none() && last.data.contains_key(*key))
+                .map(|(index, (key, _))| (index, key))
+                .unzip();
+            for (index, found) in indices.into_iter().zip(find_keys(older, &keys)) {
+                shadowed[index] = found.is_some();
+            }
+        }
+
+        let mut events = Vec::new();
+        if !self.observers.is_empty() {
+            for ((key, value), found) in batch.changes.iter().zip(&found) {
+                match (value, found.is_some()) {
+                    (Some(_), true) => events.push(Event::Updated(key.clone())),
+                    (Some(_), false) => events.push(Event::Inserted(key.clone())),
+                    (None, true) => events.push(Event::Removed(key.clone())),
+                    (None, false) => {}
+                }
+            }
+        }
+
+        let (last, older) = split_last_mut(&mut self.versions);
+        let mut previous = Vec::with_capacity(found.len());
+        let changes = batch.changes.into_iter().zip(found).zip(shadowed);
+        for (((key, value), found), shadowed) in changes {
+            // A value of an older version stays there, it is cloned
+            let shadowed_value = found
+                .and_then(|index| older.get(index))
+                .and_then(|version| version.data.get(&key).cloned());
+            let value = match value {
+                Some(value) => {
+                    if found.is_none() {
+                        last.values_count += 1;
+                    }
+                    last.removed_keys.remove(&key);
+                    last.data.insert(key.clone(), value)
+                }
+                None if found.is_some() => {
+                    last.values_count -= 1;
+                    let value = last.data.remove(&key);
+                    if value.is_none() || shadowed {
+                        last.removed_keys.insert(key.clone());
+                    }
+                    value
+                }
+                None => None,
+            };
+            previous.push((key, value.or(shadowed_value)));
+        }
+        self.debug_check();
+
+        for event in &events {
+            self.observers.notify(&event.as_ref());
+        }
+        previous
+    }
+
+    /// Inserts the key-value pairs as a single batch.
+    /// Returns every key with its previous value, in the order of the keys.
+    /// See [`RollbackMap::write`].
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(
+    ///     map.insert_many(vec![(1, "b"), (2, "c")]),
+    ///     vec![(1, Some("a")), (2, None)]
+    /// );
+    /// assert_eq!(map.get(&1), Some(&"b"));
+    /// assert_eq!(map.len(), 2);
+    /// ```
+    pub fn insert_many<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) -> Vec<(K, Option<V>)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut batch = WriteBatch::new();
+        batch.extend(iter);
+        self.write(batch)
+    }
+
+    /// Removes the keys as a single batch.
+    /// Returns every key with its removed value, in the order of the keys.
+    /// See [`RollbackMap::write`].
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.checkpoint();
+    /// assert_eq!(map.remove_many(vec![1, 3]), vec![(1, Some("a")), (3, None)]);
+    /// assert_eq!(map.get(&1), None);
+    /// assert_eq!(map.len(), 1);
+    /// ```
+    pub fn remove_many<I: IntoIterator<Item = K>>(&mut self, keys: I) -> Vec<(K, Option<V>)>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let mut batch = WriteBatch::new();
+        for key in keys {
+            batch.remove(key);
+        }
+        self.write(batch)
+    }
+}
+
+// Finds the index of the version holding the visible value of each of the sorted keys.
+// Every version is searched once for all the keys not decided by the newer ones.
+fn find_keys<K: Ord, V>(versions: &[VersionState<K, V>], keys: &[&K]) -> Vec<Option<usize>> {
+    let mut found = vec![None; keys.len()];
+    let mut pending: Vec<usize> = (0..keys.len()).collect();
+    for (version_index, version) in versions.iter().enumerate().rev() {
+        let (first, last) = match (pending.first(), pending.last()) {
+            (Some(&first), Some(&last)) => (keys[first], keys[last]),
+            _ => break,
+        };
+        let mut data = version
+            .data
+            .range(first..=last)
+            .map(|(key, _)| key)
+            .peekable();
+        let mut removed = version.removed_keys.range(first..=last).peekable();
+        pending.retain(|&index| {
+            let key = keys[index];
+            if seek(&mut data, key) {
+                found[index] = Some(version_index);
+                return false;
+            }
+            !seek(&mut removed, key) && !version.detached
+        });
+    }
+    found
+}
+
+// Advances the iterator of the sorted keys to the key, returns true if it is found.
+fn seek<'a, K: Ord + 'a, I: Iterator<Item = &'a K>>(keys: &mut Peekable<I>, key: &K) -> bool {
+    while let Some(&found) = keys.peek() {
+        if found >= key {
+            return found == key;
+        }
+        keys.next();
+    }
+    false
+}
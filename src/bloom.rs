@@ -0,0 +1,261 @@
+//! Rollback map skipping the layers that do not know a key on lookup.
+//!
+//! A lookup in a [`RollbackMap`] probes the layers from the newest one until one of them
+//! has the key or its tombstone, so a miss probes all of them.
+//! [`BloomRollbackMap`] keeps a Bloom filter of the keys of every layer saved by
+//! a checkpoint and probes only the layers whose filters may contain the key.
+//! The filters are built when a checkpoint is created, and rebuilt for the layers
+//! that are folded together by [`RollbackMap::prune`] or by the memory budget.
+//! The current layer has no filter, it is always probed.
+use crate::rollbackmap::{RollbackMap, VersionState};
+use core::borrow::Borrow;
+use core::hash::{Hash, Hasher};
+use core::ops::Deref;
+use std::collections::hash_map::DefaultHasher;
+
+/// Size of the filters if not given, about 1% of the missing keys pass a filter.
+pub const DEFAULT_BITS_PER_KEY: usize = 10;
+
+/// A [`RollbackMap`] with Bloom filters of the layers saved by the checkpoints.
+///
+/// The keys have to implement `Hash`. Read access to the wrapped map is available
+/// through `Deref`, `get` and `contains_key` use the filters.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::bloom::BloomRollbackMap;
+///
+/// let mut map = BloomRollbackMap::new();
+/// for checkpoint in 0..100 {
+///     map.insert(checkpoint, "a");
+///     map.checkpoint();
+/// }
+/// map.remove(&7);
+///
+/// assert_eq!(map.get(&42), Some(&"a"));
+/// assert_eq!(map.get(&7), None);
+/// assert!(!map.contains_key(&1000));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BloomRollbackMap<K: Ord, V> {
+    map: RollbackMap<K, V>,
+    // Filters of the versions saved by the checkpoints, all but the current one.
+    filters: Vec<LayerFilter>,
+    bits_per_key: usize,
+}
+
+#[derive(Clone, Debug)]
+struct LayerFilter {
+    // Checkpoint of the version the filter was built for,
+    // folding versions together changes the checkpoints of the following ones.
+    checkpoint: u32,
+    bloom: Bloom,
+}
+
+impl<K: Ord + Hash, V> Default for BloomRollbackMap<K, V> {
+    /// Creates an empty `BloomRollbackMap`.
+    fn default() -> Self {
+        BloomRollbackMap::new()
+    }
+}
+
+impl<K: Ord + Hash, V> BloomRollbackMap<K, V> {
+    /// Makes a new, empty `BloomRollbackMap` with [`DEFAULT_BITS_PER_KEY`].
+    pub fn new() -> Self {
+        Self::from_map(RollbackMap::new(), DEFAULT_BITS_PER_KEY)
+    }
+
+    /// Wraps the map, building the filters of its checkpoints right away.
+    ///
+    /// The filters take `bits_per_key` bits for every key or tombstone of a layer,
+    /// it is raised to 1 if lower.
+    pub fn from_map(map: RollbackMap<K, V>, bits_per_key: usize) -> Self {
+        let mut map = BloomRollbackMap {
+            map,
+            filters: Vec::new(),
+            bits_per_key: bits_per_key.max(1),
+        };
+        map.sync();
+        map
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    /// See [`RollbackMap::contains_key`].
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    /// See [`RollbackMap::get`].
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + Hash + ?Sized,
+    {
+        // Keys hash the same as their borrowed forms
+        let hash = hash_of(key);
+        for (index, version) in self.map.versions.iter().enumerate().rev() {
+            let probe = match self.filters.get(index) {
+                Some(filter) => filter.bloom.may_contain(hash),
+                None => true,
+            };
+            if probe {
+                if let Some(value) = version.data.get(key) {
+                    return Some(value);
+                }
+                if version.removed_keys.contains(key) {
+                    return None;
+                }
+            }
+            if version.detached {
+                return None;
+            }
+        }
+        None
+    }
+
+    /// Inserts a key-value pair into the map.
+    /// See [`RollbackMap::insert`].
+    pub fn insert(&mut self, key: K, value: V) -> Option<V>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        self.map.insert(key, value)
+    }
+
+    /// Removes a key from the map.
+    /// See [`RollbackMap::remove`].
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        self.map.remove(key)
+    }
+
+    /// Clears the map.
+    /// See [`RollbackMap::clear`].
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Creates a checkpoint and builds the filter of its layer.
+    /// See [`RollbackMap::checkpoint`].
+    pub fn checkpoint(&mut self) -> Option<u32> {
+        let checkpoint = self.map.checkpoint();
+        self.sync();
+        checkpoint
+    }
+
+    /// Rollbacks to saved checkpoint.
+    /// See [`RollbackMap::rollback`].
+    pub fn rollback(&mut self, checkpoint: u32) -> bool {
+        let rollback = self.map.rollback(checkpoint);
+        self.sync();
+        rollback
+    }
+
+    /// Deletes all the checkpoints except the last one, rebuilding the filters
+    /// of the folded layers.
+    /// See [`RollbackMap::prune`].
+    pub fn prune(&mut self) -> Option<u32> {
+        let checkpoint = self.map.prune();
+        self.sync();
+        checkpoint
+    }
+
+    /// Returns the wrapped map, dropping the filters.
+    pub fn into_inner(self) -> RollbackMap<K, V> {
+        self.map
+    }
+
+    // Drops the filters of the versions that were rolled back or folded,
+    // and builds the missing ones.
+    fn sync(&mut self) {
+        let saved = &self.map.versions[..self.map.versions.len() - 1];
+        let valid = self
+            .filters
+            .iter()
+            .zip(saved)
+            .take_while(|(filter, version)| filter.checkpoint == version.checkpoint)
+            .count();
+        self.filters.truncate(valid);
+        for version in &saved[valid..] {
+            self.filters.push(LayerFilter {
+                checkpoint: version.checkpoint,
+                bloom: Bloom::build(version, self.bits_per_key),
+            });
+        }
+    }
+}
+
+impl<K: Ord, V> Deref for BloomRollbackMap<K, V> {
+    type Target = RollbackMap<K, V>;
+
+    fn deref(&self) -> &RollbackMap<K, V> {
+        &self.map
+    }
+}
+
+// Bloom filter of the keys and the tombstones of a version,
+// probed at the positions derived from a single hash by double hashing.
+#[derive(Clone, Debug)]
+struct Bloom {
+    words: Vec<u64>,
+    probes: u32,
+}
+
+impl Bloom {
+    fn build<K: Hash, V>(version: &VersionState<K, V>, bits_per_key: usize) -> Self {
+        let keys = version.data.len() + version.removed_keys.len();
+        let words = if keys == 0 {
+            0
+        } else {
+            (keys * bits_per_key).div_ceil(64)
+        };
+        // ln(2) * bits per key probes minimize the false positives
+        let probes = ((bits_per_key as f64 * 0.69).round() as u32).clamp(1, 16);
+        let mut bloom = Bloom {
+            words: vec![0; words],
+            probes,
+        };
+        let hashes = version.data.keys().chain(version.removed_keys.iter());
+        for hash in hashes.map(hash_of) {
+            for bit in bloom.positions(hash) {
+                bloom.words[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+        bloom
+    }
+
+    fn may_contain(&self, hash: u64) -> bool {
+        !self.words.is_empty()
+            && self
+                .positions(hash)
+                .all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    fn positions(&self, hash: u64) -> impl Iterator<Item = usize> {
+        let bits = self.words.len() as u64 * 64;
+        let first = hash & 0xffff_ffff;
+        // A zero step would probe a single position
+        let step = (hash >> 32) | 1;
+        (0..u64::from(self.probes))
+            .map(move |probe| (first.wrapping_add(probe.wrapping_mul(step)) % bits) as usize)
+    }
+}
+
+fn hash_of<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
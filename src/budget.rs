@@ -0,0 +1,199 @@
+//! Memory budget of the checkpoints of a [`RollbackMap`].
+//!
+//! With a budget set by [`RollbackMap::set_memory_budget`], the map tracks the estimated
+//! size of every layer saved by a checkpoint. Whenever the layers take more than the
+//! budget, the oldest checkpoints expire: their layers are folded into the newer ones
+//! like [`RollbackMap::prune`] does, and [`Event::CheckpointsExpired`] is emitted.
+//!
+//! The oldest layer is the base the expired layers are folded into, and the current
+//! layer holds the changes since the last checkpoint; neither of them is counted.
+//! The last checkpoint and the pinned ones never expire, so the budget can be exceeded.
+use crate::events::Event;
+use crate::rollbackmap::{fold, RollbackMap, VersionState};
+use crate::stats::layer_size;
+use core::fmt;
+use std::sync::Arc;
+
+type Estimator<T> = Arc<dyn Fn(&T) -> usize + Send + Sync>;
+
+// Limit of the size of the saved versions with their tracked sizes.
+pub(crate) struct Budget<K, V> {
+    limit: usize,
+    key_size: Estimator<K>,
+    value_size: Estimator<V>,
+    // Sizes of the versions saved by the checkpoints, all but the current one.
+    sizes: Vec<usize>,
+    total: usize,
+}
+
+impl<K, V> Budget<K, V> {
+    // Size of the saved versions above the base one.
+    fn history(&self) -> usize {
+        self.total - self.sizes.first().copied().unwrap_or(0)
+    }
+
+    // Forgets the sizes of the versions dropped by a rollback.
+    pub(crate) fn truncate(&mut self, saved: usize) {
+        if saved < self.sizes.len() {
+            self.total -= self.sizes[saved..].iter().sum::<usize>();
+            self.sizes.truncate(saved);
+        }
+    }
+
+    // Forgets all the sizes, for the versions were rearranged.
+    pub(crate) fn reset(&mut self) {
+        self.sizes.clear();
+        self.total = 0;
+    }
+
+    // Estimates the sizes of the versions saved since the last call.
+    fn track(&mut self, versions: &[VersionState<K, V>]) {
+        let saved = &versions[..versions.len() - 1];
+        self.truncate(saved.len());
+        for version in &saved[self.sizes.len()..] {
+            let size = self.size(version);
+            self.sizes.push(size);
+            self.total += size;
+        }
+    }
+
+    fn size(&self, version: &VersionState<K, V>) -> usize {
+        layer_size(version, &mut |key| (self.key_size)(key), &mut |value| {
+            (self.value_size)(value)
+        })
+    }
+}
+
+impl<K, V> Clone for Budget<K, V> {
+    fn clone(&self) -> Self {
+        Budget {
+            limit: self.limit,
+            key_size: Arc::clone(&self.key_size),
+            value_size: Arc::clone(&self.value_size),
+            sizes: self.sizes.clone(),
+            total: self.total,
+        }
+    }
+}
+
+impl<K, V> fmt::Debug for Budget<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Budget")
+            .field("limit", &self.limit)
+            .field("sizes", &self.sizes)
+            .finish()
+    }
+}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Limits the estimated size of the checkpoints to `limit` bytes,
+    /// expiring the oldest ones right away if they take more.
+    ///
+    /// The sizes are estimated like [`RollbackMap::stats_with`] does:
+    /// `key_size` and `value_size` return the size of the memory owned by a key
+    /// and by a value in addition to their `size_of`.
+    /// The budget replaces the previous one, and is cloned with the map.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::events::Event;
+    /// use rollbackmap::RollbackMap;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let expired: Arc<Mutex<Vec<u32>>> = Arc::default();
+    /// let mut map: RollbackMap<u32, String> = RollbackMap::new();
+    /// let sink = Arc::clone(&expired);
+    /// map.subscribe(move |event: &Event<&u32>| {
+    ///     if let Event::CheckpointsExpired(checkpoints) = event {
+    ///         sink.lock().unwrap().extend(checkpoints);
+    ///     }
+    /// });
+    /// map.set_memory_budget(1500, |_| 0, |value: &String| value.capacity());
+    ///
+    /// let mut checkpoints = Vec::new();
+    /// for key in 0..3 {
+    ///     map.insert(key, "a".repeat(1000));
+    ///     checkpoints.extend(map.checkpoint());
+    /// }
+    ///
+    /// assert_eq!(*expired.lock().unwrap(), vec![checkpoints[0]]);
+    /// assert_eq!(map.get_checkpoints_count(), 2);
+    /// assert!(map.history_size().unwrap() <= 1500);
+    /// assert_eq!(map.len(), 3);
+    /// ```
+    pub fn set_memory_budget<F, G>(&mut self, limit: usize, key_size: F, value_size: G)
+    where
+        F: Fn(&K) -> usize + Send + Sync + 'static,
+        G: Fn(&V) -> usize + Send + Sync + 'static,
+    {
+        self.budget = Some(Budget {
+            limit,
+            key_size: Arc::new(key_size),
+            value_size: Arc::new(value_size),
+            sizes: Vec::new(),
+            total: 0,
+        });
+        self.enforce_budget();
+        self.debug_check();
+    }
+
+    /// Removes the memory budget, the checkpoints are kept until pruned then.
+    pub fn clear_memory_budget(&mut self) {
+        self.budget = None;
+    }
+
+    /// Returns the memory budget in bytes, if set.
+    pub fn memory_budget(&self) -> Option<usize> {
+        self.budget.as_ref().map(|budget| budget.limit)
+    }
+
+    /// Returns the estimated size in bytes of the checkpoints counted by the memory budget,
+    /// if it is set.
+    pub fn history_size(&self) -> Option<usize> {
+        self.budget.as_ref().map(Budget::history)
+    }
+
+    // Expires the oldest checkpoints until their layers fit the budget.
+    pub(crate) fn enforce_budget(&mut self) {
+        let budget = match self.budget.as_mut() {
+            Some(budget) => budget,
+            None => return,
+        };
+        budget.track(&self.versions);
+
+        let mut expired = Vec::new();
+        let mut index = 0;
+        // The last checkpoint is kept
+        while budget.history() > budget.limit && index + 2 < self.versions.len() {
+            let checkpoint = self.versions[index].checkpoint;
+            if self.pins.is_pinned(checkpoint) {
+                index += 1;
+                continue;
+            }
+            if !self.versions[index].retired {
+                expired.push(checkpoint);
+            }
+
+            let newer = self.versions.remove(index + 1);
+            let (below, rest) = self.versions.split_at_mut(index);
+            let folded = &mut rest[0];
+            fold(folded, newer, below);
+            // Nothing is left below to remove keys from
+            if index == 0 || folded.detached {
+                folded.removed_keys.clear();
+            }
+            let size = budget.size(folded);
+            budget.total = budget.total + size - budget.sizes[index] - budget.sizes[index + 1];
+            budget.sizes[index] = size;
+            budget.sizes.remove(index + 1);
+        }
+
+        if !expired.is_empty() {
+            let expired = Event::CheckpointsExpired(expired);
+            self.observers.notify(&expired);
+        }
+    }
+}
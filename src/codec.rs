@@ -0,0 +1,238 @@
+//! Binary encoding of keys and values.
+//!
+//! The binary formats of this crate (snapshots, journals) do not know how to
+//! encode `K` and `V`, so the caller supplies a [`Codec`] for each of them.
+//! Codecs for the common primitive types are provided.
+use std::io::{self, Read, Write};
+
+/// Encodes and decodes values of type `T` to and from a byte stream.
+///
+/// `decode` must read exactly the bytes written by `encode`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::codec::Codec;
+/// use std::io::{self, Read, Write};
+///
+/// struct Point;
+///
+/// impl Codec<(i8, i8)> for Point {
+///     fn encode(&self, value: &(i8, i8), writer: &mut dyn Write) -> io::Result<()> {
+///         writer.write_all(&[value.0 as u8, value.1 as u8])
+///     }
+///
+///     fn decode(&self, reader: &mut dyn Read) -> io::Result<(i8, i8)> {
+///         let mut bytes = [0u8; 2];
+///         reader.read_exact(&mut bytes)?;
+///         Ok((bytes[0] as i8, bytes[1] as i8))
+///     }
+/// }
+///
+/// let mut bytes = Vec::new();
+/// Point.encode(&(1, -1), &mut bytes).unwrap();
+/// assert_eq!(Point.decode(&mut bytes.as_slice()).unwrap(), (1, -1));
+/// ```
+pub trait Codec<T> {
+    /// Writes the value to the writer.
+    fn encode(&self, value: &T, writer: &mut dyn Write) -> io::Result<()>;
+
+    /// Reads a value from the reader.
+    fn decode(&self, reader: &mut dyn Read) -> io::Result<T>;
+}
+
+/// Fixed size little-endian codec for the primitive numeric types and `bool`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LittleEndian;
+
+macro_rules! little_endian_codec {
+    ($($t:ty),*) => {
+        $(
+            impl Codec<$t> for LittleEndian {
+                fn encode(&self, value: &$t, writer: &mut dyn Write) -> io::Result<()> {
+                    writer.write_all(&value.to_le_bytes())
+                }
+
+                fn decode(&self, reader: &mut dyn Read) -> io::Result<$t> {
+                    let mut bytes = [0u8; std::mem::size_of::<$t>()];
+                    reader.read_exact(&mut bytes)?;
+                    Ok(<$t>::from_le_bytes(bytes))
+                }
+            }
+        )*
+    };
+}
+
+little_endian_codec!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128, f32, f64);
+
+impl Codec<bool> for LittleEndian {
+    fn encode(&self, value: &bool, writer: &mut dyn Write) -> io::Result<()> {
+        write_u8(writer, u8::from(*value))
+    }
+
+    fn decode(&self, reader: &mut dyn Read) -> io::Result<bool> {
+        match read_u8(reader)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(invalid_data("invalid bool value")),
+        }
+    }
+}
+
+/// Codec for `String` and `Vec<u8>` that writes the length as `u64` followed by the bytes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LengthPrefixed;
+
+impl Codec<Vec<u8>> for LengthPrefixed {
+    fn encode(&self, value: &Vec<u8>, writer: &mut dyn Write) -> io::Result<()> {
+        write_len(writer, value.len())?;
+        writer.write_all(value)
+    }
+
+    fn decode(&self, reader: &mut dyn Read) -> io::Result<Vec<u8>> {
+        let len = read_len(reader)?;
+        let mut bytes = Vec::new();
+        reader.take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(bytes)
+    }
+}
+
+impl Codec<String> for LengthPrefixed {
+    fn encode(&self, value: &String, writer: &mut dyn Write) -> io::Result<()> {
+        write_len(writer, value.len())?;
+        writer.write_all(value.as_bytes())
+    }
+
+    fn decode(&self, reader: &mut dyn Read) -> io::Result<String> {
+        let bytes: Vec<u8> = self.decode(reader)?;
+        String::from_utf8(bytes).map_err(|_| invalid_data("invalid UTF-8 string"))
+    }
+}
+
+pub(crate) fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+pub(crate) fn write_u8(writer: &mut dyn Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub(crate) fn read_u8(reader: &mut dyn Read) -> io::Result<u8> {
+    let mut bytes = [0u8; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+pub(crate) fn write_u32(writer: &mut dyn Write, value: u32) -> io::Result<()> {
+    LittleEndian.encode(&value, writer)
+}
+
+pub(crate) fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    LittleEndian.decode(reader)
+}
+
+pub(crate) fn write_len(writer: &mut dyn Write, len: usize) -> io::Result<()> {
+    LittleEndian.encode(&(len as u64), writer)
+}
+
+pub(crate) fn read_len(reader: &mut dyn Read) -> io::Result<usize> {
+    let len: u64 = LittleEndian.decode(reader)?;
+    if len > usize::MAX as u64 {
+        return Err(invalid_data("length does not fit into usize"));
+    }
+    Ok(len as usize)
+}
+
+/// CRC-32 (IEEE 802.3) checksum.
+#[derive(Clone, Debug)]
+pub(crate) struct Crc32 {
+    table: [u32; 256],
+    value: u32,
+}
+
+impl Crc32 {
+    pub(crate) fn new() -> Self {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut crc = n as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    0xEDB8_8320 ^ (crc >> 1)
+                } else {
+                    crc >> 1
+                };
+            }
+            *entry = crc;
+        }
+        Crc32 {
+            table,
+            value: 0xFFFF_FFFF,
+        }
+    }
+
+    pub(crate) fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            let index = (self.value ^ u32::from(*byte)) & 0xFF;
+            self.value = self.table[index as usize] ^ (self.value >> 8);
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u32 {
+        !self.value
+    }
+}
+
+/// Writer that computes the checksum of everything written through it.
+pub(crate) struct ChecksumWriter<W> {
+    pub(crate) inner: W,
+    pub(crate) crc: Crc32,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.crc.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reader that computes the checksum of everything read through it.
+pub(crate) struct ChecksumReader<R> {
+    pub(crate) inner: R,
+    pub(crate) crc: Crc32,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        ChecksumReader {
+            inner,
+            crc: Crc32::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.crc.update(&buf[..read]);
+        Ok(read)
+    }
+}
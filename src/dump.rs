@@ -0,0 +1,191 @@
+//! Readable dumps of the layers of a [`RollbackMap`].
+//!
+//! [`RollbackMap::dump_layers`] lists the changes saved by every checkpoint,
+//! and [`RollbackMap::to_dot`] exports the chain of the layers to Graphviz.
+//! They are meant for diagnosing, the output format may change.
+use crate::rollbackmap::{lookup, Lookup, RollbackMap};
+use core::fmt::{self, Debug, Display, Write};
+
+/// Changes of a single layer of a [`RollbackMap`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerDump<'a, K, V> {
+    /// Checkpoint of the layer, the one to be created next for the current layer.
+    pub checkpoint: u32,
+    /// Is set for the current layer, holding the changes since the last checkpoint.
+    pub current: bool,
+    /// Is set if the map was cleared in the layer.
+    pub detached: bool,
+    /// Is set if the checkpoint was pruned, but its layer is kept for a pin.
+    pub retired: bool,
+    /// Keys inserted in the layer that were not visible before it.
+    pub added: Vec<(&'a K, &'a V)>,
+    /// Keys whose visible values were replaced in the layer.
+    pub updated: Vec<(&'a K, &'a V)>,
+    /// Keys removed in the layer.
+    pub removed: Vec<&'a K>,
+}
+
+/// Changes of all the layers of a [`RollbackMap`], from the oldest one.
+///
+/// Returned by [`RollbackMap::dump_layers`], displayed one change per line.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dump<'a, K, V> {
+    /// Layers from the oldest one, the last one is the current layer.
+    pub layers: Vec<LayerDump<'a, K, V>>,
+}
+
+impl<'a, K, V> LayerDump<'a, K, V> {
+    fn title(&self) -> String {
+        let mut title = if self.current {
+            format!("current, next checkpoint {}", self.checkpoint)
+        } else {
+            format!("checkpoint {}", self.checkpoint)
+        };
+        match (self.detached, self.retired) {
+            (true, true) => title.push_str(" (detached, retired)"),
+            (true, false) => title.push_str(" (detached)"),
+            (false, true) => title.push_str(" (retired)"),
+            (false, false) => {}
+        }
+        title
+    }
+}
+
+impl<'a, K: Debug, V: Debug> Display for LayerDump<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", self.title())?;
+        for (key, value) in &self.added {
+            writeln!(f, "  + {:?}: {:?}", key, value)?;
+        }
+        for (key, value) in &self.updated {
+            writeln!(f, "  ~ {:?}: {:?}", key, value)?;
+        }
+        for key in &self.removed {
+            writeln!(f, "  - {:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, K: Debug, V: Debug> Display for Dump<'a, K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for layer in &self.layers {
+            write!(f, "{}", layer)?;
+        }
+        Ok(())
+    }
+}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Returns the changes of every layer of the map, from the oldest one.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.checkpoint();
+    /// map.insert(1, "c");
+    /// map.insert(3, "d");
+    /// map.remove(&2);
+    ///
+    /// assert_eq!(
+    ///     map.dump_layers().to_string(),
+    ///     "checkpoint 0\n  + 1: \"a\"\n  + 2: \"b\"\n\
+    ///      current, next checkpoint 1\n  + 3: \"d\"\n  ~ 1: \"c\"\n  - 2\n"
+    /// );
+    /// ```
+    pub fn dump_layers(&self) -> Dump<'_, K, V> {
+        let last_index = self.versions.len() - 1;
+        let layers = self
+            .versions
+            .iter()
+            .enumerate()
+            .map(|(index, version)| {
+                let older = &self.versions[..index];
+                let mut added = Vec::new();
+                let mut updated = Vec::new();
+                for (key, value) in &version.data {
+                    match lookup(older, key) {
+                        Lookup::Found(..) if !version.detached => updated.push((key, value)),
+                        _ => added.push((key, value)),
+                    }
+                }
+                LayerDump {
+                    checkpoint: version.checkpoint,
+                    current: index == last_index,
+                    detached: version.detached,
+                    retired: version.retired,
+                    added,
+                    updated,
+                    removed: version.removed_keys.iter().collect(),
+                }
+            })
+            .collect();
+        Dump { layers }
+    }
+
+    /// Exports the chain of the layers in the Graphviz DOT format.
+    ///
+    /// Every layer is a node labeled with its checkpoint and change counts,
+    /// an edge leads from every layer to the newer one. The history of the map is linear,
+    /// so the graph is a chain; an edge into a cleared layer is dashed,
+    /// and the retired layers are grayed.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.checkpoint();
+    /// map.clear();
+    ///
+    /// let dot = map.to_dot();
+    /// assert!(dot.starts_with("digraph rollbackmap {"));
+    /// assert!(dot.contains("v0 -> v1 [style=dashed, label=\"clear\"];"));
+    /// ```
+    pub fn to_dot(&self) -> String {
+        let mut dot =
+            String::from("digraph rollbackmap {\n    rankdir=LR;\n    node [shape=box];\n");
+        let dump = self.dump_layers();
+        for (index, layer) in dump.layers.iter().enumerate() {
+            let style = if layer.retired {
+                ", style=filled, fillcolor=lightgray"
+            } else if layer.current {
+                ", style=bold"
+            } else {
+                ""
+            };
+            // Writing to a String never fails
+            let _ = writeln!(
+                dot,
+                "    v{} [label=\"{}\\n+{} ~{} -{}\"{}];",
+                index,
+                layer.title(),
+                layer.added.len(),
+                layer.updated.len(),
+                layer.removed.len(),
+                style
+            );
+        }
+        for (index, layer) in dump.layers.iter().enumerate().skip(1) {
+            let style = if layer.detached {
+                " [style=dashed, label=\"clear\"]"
+            } else {
+                ""
+            };
+            let _ = writeln!(dot, "    v{} -> v{}{};", index - 1, index, style);
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
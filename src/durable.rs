@@ -0,0 +1,443 @@
+//! File-backed durable [`RollbackMap`].
+//!
+//! [`DurableRollbackMap`] keeps the whole map in memory and persists it to a directory:
+//! - every mutation is appended to a [journal] file;
+//! - `checkpoint`, `rollback` and `prune` are commit points, the journal is synced to disk
+//!   before they return;
+//! - [`DurableRollbackMap::compact`] writes a [snapshot](crate::snapshot) of the map
+//!   and starts a new, empty journal.
+//!
+//! Reopening the directory restores the map as of the last commit point.
+//! Mutations journaled after it, as well as a partially written last record, are discarded.
+//! A write that fails is cut off the journal right away, so the records journaled after it
+//! are still recovered.
+//!
+//! The directory holds the files of a single generation: `snapshot-<generation>.bin`
+//! (absent for the generation 0) and `journal-<generation>.log` with the records
+//! to apply on top of the snapshot.
+use crate::codec::Codec;
+use crate::journal::{self, JournaledRollbackMap, Record};
+use crate::rollbackmap::RollbackMap;
+use core::borrow::Borrow;
+use core::ops::Deref;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+const SNAPSHOT_SUFFIX: &str = ".bin";
+const JOURNAL_PREFIX: &str = "journal-";
+const JOURNAL_SUFFIX: &str = ".log";
+const TEMPORARY_SUFFIX: &str = ".tmp";
+
+/// A [`RollbackMap`] persisted to a local directory.
+///
+/// Read access to the in-memory map is available through `Deref`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::codec::LittleEndian;
+/// use rollbackmap::durable::DurableRollbackMap;
+///
+/// let dir = std::env::temp_dir().join(format!("rollbackmap-doc-{}", std::process::id()));
+/// {
+///     let mut map = DurableRollbackMap::open(&dir, LittleEndian, LittleEndian).unwrap();
+///     map.insert(1u32, 10u64).unwrap();
+///     map.checkpoint().unwrap();
+///     // not committed by a checkpoint, lost on reopen
+///     map.insert(2, 20).unwrap();
+/// }
+///
+/// let map = DurableRollbackMap::<u32, u64, _, _>::open(&dir, LittleEndian, LittleEndian).unwrap();
+/// assert_eq!(map.get(&1), Some(&10));
+/// assert_eq!(map.get(&2), None);
+/// assert_eq!(map.get_checkpoints_count(), 1);
+/// # std::fs::remove_dir_all(&dir).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct DurableRollbackMap<K: Ord, V, KC, VC> {
+    pub(crate) journal: JournaledRollbackMap<K, V, JournalFile, KC, VC>,
+    dir: PathBuf,
+    generation: u64,
+}
+
+impl<K, V, KC, VC> DurableRollbackMap<K, V, KC, VC>
+where
+    K: Ord,
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    /// Opens the map persisted in the directory, creating both if they do not exist.
+    ///
+    /// The map is restored as of the last commit point,
+    /// the journal records written after it are discarded.
+    ///
+    /// Returns an error of the `InvalidData` kind if the snapshot is corrupted,
+    /// a journal record other than the last one does not match its checksum,
+    /// or a record cannot be decoded with the supplied codecs.
+    /// The files are left untouched then.
+    pub fn open<P: AsRef<Path>>(dir: P, key_codec: KC, value_codec: VC) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let generation = last_generation(&dir)?;
+        let map = if generation == 0 {
+            RollbackMap::new()
+        } else {
+            let snapshot = File::open(snapshot_path(&dir, generation))?;
+            RollbackMap::read_from(BufReader::new(snapshot), &key_codec, &value_codec)?
+        };
+        remove_other_generations(&dir, generation)?;
+
+        let journal_path = journal_path(&dir, generation);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&journal_path)?;
+        let (map, committed_len) = recover(map, &mut file, &key_codec, &value_codec)?;
+        if committed_len == 0 {
+            file.set_len(0)?;
+            journal::write_header(&mut file)?;
+        } else {
+            file.set_len(committed_len)?;
+        }
+        let len = file.seek(SeekFrom::End(0))?;
+        file.sync_all()?;
+        sync_dir(&dir)?;
+
+        let file = JournalFile::new(file, len);
+        Ok(DurableRollbackMap {
+            journal: JournaledRollbackMap::resume(map, file, key_codec, value_codec),
+            dir,
+            generation,
+        })
+    }
+
+    /// Inserts a key-value pair into the map.
+    /// See [`RollbackMap::insert`].
+    ///
+    /// The insertion is durable after the next commit point.
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<V>>
+    where
+        V: Clone,
+    {
+        self.journaled(|journal| journal.insert(key, value))
+    }
+
+    /// Removes a key from the map.
+    /// See [`RollbackMap::remove`].
+    ///
+    /// The removal is durable after the next commit point.
+    pub fn remove<Q>(&mut self, key: &Q) -> io::Result<Option<V>>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        self.journaled(|journal| journal.remove(key))
+    }
+
+    /// Clears the map.
+    /// See [`RollbackMap::clear`].
+    ///
+    /// The clearing is durable after the next commit point.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.journaled(|journal| journal.clear())
+    }
+
+    /// Creates a checkpoint and makes it durable.
+    /// See [`RollbackMap::checkpoint`].
+    pub fn checkpoint(&mut self) -> io::Result<Option<u32>> {
+        let checkpoint = self.journaled(|journal| journal.checkpoint())?;
+        self.journal.writer.file.sync_data()?;
+        Ok(checkpoint)
+    }
+
+    /// Rollbacks to the checkpoint and makes the rollback durable.
+    /// See [`RollbackMap::rollback`].
+    pub fn rollback(&mut self, checkpoint: u32) -> io::Result<bool> {
+        let rollback = self.journaled(|journal| journal.rollback(checkpoint))?;
+        if rollback {
+            self.journal.writer.file.sync_data()?;
+        }
+        Ok(rollback)
+    }
+
+    /// Deletes all the checkpoints except the last one and makes it durable.
+    /// See [`RollbackMap::prune`].
+    pub fn prune(&mut self) -> io::Result<Option<u32>> {
+        let checkpoint = self.journaled(|journal| journal.prune())?;
+        self.journal.writer.file.sync_data()?;
+        Ok(checkpoint)
+    }
+
+    /// Replaces the journal by a snapshot of the current map.
+    ///
+    /// The whole current map, including the changes made after the last commit point,
+    /// becomes durable. Reopening no longer needs to replay the old journal records.
+    pub fn compact(&mut self) -> io::Result<()> {
+        let generation = self.generation + 1;
+
+        let snapshot_path = snapshot_path(&self.dir, generation);
+        let temporary_path = temporary_path(&snapshot_path);
+        {
+            let mut file = File::create(&temporary_path)?;
+            self.journal.map.write_to(
+                BufWriter::new(&mut file),
+                &self.journal.key_codec,
+                &self.journal.value_codec,
+            )?;
+            file.sync_all()?;
+        }
+
+        let mut journal = File::create(journal_path(&self.dir, generation))?;
+        journal::write_header(&mut journal)?;
+        journal.sync_all()?;
+        let len = journal.stream_position()?;
+
+        // The renaming makes the new generation the last one.
+        fs::rename(&temporary_path, &snapshot_path)?;
+        sync_dir(&self.dir)?;
+
+        self.journal.writer = JournalFile::new(journal, len);
+        self.journal.poisoned = None;
+        self.generation = generation;
+        remove_other_generations(&self.dir, generation)
+    }
+
+    /// Returns the directory the map is persisted to.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the in-memory map.
+    pub fn into_inner(self) -> RollbackMap<K, V> {
+        self.journal.into_parts().0
+    }
+
+    // Runs the journaled operation. A record the operation failed to write is cut off
+    // the journal, so the next records follow the last complete one.
+    // The journal stays poisoned if it cannot be cut.
+    fn journaled<R, F>(&mut self, operation: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut JournaledRollbackMap<K, V, JournalFile, KC, VC>) -> io::Result<R>,
+    {
+        let len = self.journal.writer.len;
+        let result = operation(&mut self.journal);
+        if self.journal.is_poisoned() && self.journal.writer.truncate(len).is_ok() {
+            self.journal.poisoned = None;
+        }
+        result
+    }
+}
+
+impl<K: Ord, V, KC, VC> Deref for DurableRollbackMap<K, V, KC, VC> {
+    type Target = RollbackMap<K, V>;
+
+    fn deref(&self) -> &RollbackMap<K, V> {
+        &self.journal
+    }
+}
+
+// Replays the journal on top of the map up to the last checkpoint event.
+// Returns the recovered map and the journal length that has to be kept,
+// 0 if even the journal header is missing.
+fn recover<K, V, KC, VC>(
+    map: RollbackMap<K, V>,
+    file: &mut File,
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<(RollbackMap<K, V>, u64)>
+where
+    K: Ord,
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    let mut map = map;
+    let mut reader = CountingReader {
+        inner: BufReader::new(&mut *file),
+        count: 0,
+    };
+    match journal::read_header(&mut reader) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok((map, 0)),
+        Err(error) => return Err(error),
+    }
+
+    let mut committed_len = reader.count;
+    let mut uncommitted: Vec<Record<K, V>> = Vec::new();
+    loop {
+        let payload = match journal::read_frame(&mut reader) {
+            Ok(Some(payload)) => payload,
+            Ok(None) => break,
+            // A record torn by a crash, nothing after it was committed.
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => break,
+            // Only the last record may be torn, a corrupted one before others
+            // would lose committed records.
+            Err(error) if error.kind() == io::ErrorKind::InvalidData => {
+                if reader.read(&mut [0u8])? == 0 {
+                    break;
+                }
+                return Err(error);
+            }
+            Err(error) => return Err(error),
+        };
+        let record = journal::decode_record(&payload, key_codec, value_codec)?;
+        let commit = record.is_checkpoint_event();
+        uncommitted.push(record);
+        if commit {
+            for record in uncommitted.drain(..) {
+                map.apply(record)?;
+            }
+            committed_len = reader.count;
+        }
+    }
+    Ok((map, committed_len))
+}
+
+// The journal file, with its length tracked to cut a failed write off.
+#[derive(Debug)]
+pub(crate) struct JournalFile {
+    file: File,
+    len: u64,
+    // The number of bytes written before the writes fail, to test the recovery
+    // from a failed write.
+    #[cfg(test)]
+    pub(crate) fail_after: Option<usize>,
+}
+
+impl JournalFile {
+    fn new(file: File, len: u64) -> Self {
+        JournalFile {
+            file,
+            len,
+            #[cfg(test)]
+            fail_after: None,
+        }
+    }
+
+    fn truncate(&mut self, len: u64) -> io::Result<()> {
+        self.file.set_len(len)?;
+        self.file.seek(SeekFrom::Start(len))?;
+        self.len = len;
+        Ok(())
+    }
+}
+
+impl Write for JournalFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        #[cfg(test)]
+        let buf = match self.fail_after {
+            Some(0) => return Err(io::Error::other("injected write failure")),
+            Some(fail_after) => {
+                let buf = &buf[..buf.len().min(fail_after)];
+                self.fail_after = Some(fail_after - buf.len());
+                buf
+            }
+            None => buf,
+        };
+        let written = self.file.write(buf)?;
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: u64,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.count += read as u64;
+        Ok(read)
+    }
+}
+
+fn snapshot_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{}{}",
+        SNAPSHOT_PREFIX, generation, SNAPSHOT_SUFFIX
+    ))
+}
+
+fn journal_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!(
+        "{}{}{}",
+        JOURNAL_PREFIX, generation, JOURNAL_SUFFIX
+    ))
+}
+
+fn temporary_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(TEMPORARY_SUFFIX);
+    PathBuf::from(name)
+}
+
+fn generation_of(name: &str, prefix: &str, suffix: &str) -> Option<u64> {
+    if !name.starts_with(prefix)
+        || !name.ends_with(suffix)
+        || name.len() < prefix.len() + suffix.len()
+    {
+        return None;
+    }
+    name[prefix.len()..name.len() - suffix.len()].parse().ok()
+}
+
+// The generation of the newest complete snapshot, 0 if there is none.
+fn last_generation(dir: &Path) -> io::Result<u64> {
+    let mut last = 0;
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        if let Some(generation) = name
+            .to_str()
+            .and_then(|name| generation_of(name, SNAPSHOT_PREFIX, SNAPSHOT_SUFFIX))
+        {
+            last = last.max(generation);
+        }
+    }
+    Ok(last)
+}
+
+// Removes the files of the older generations and the unfinished files of the newer ones.
+fn remove_other_generations(dir: &Path, generation: u64) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        let other = if name.ends_with(TEMPORARY_SUFFIX) {
+            true
+        } else {
+            let file_generation = generation_of(name, SNAPSHOT_PREFIX, SNAPSHOT_SUFFIX)
+                .or_else(|| generation_of(name, JOURNAL_PREFIX, JOURNAL_SUFFIX));
+            matches!(file_generation, Some(file_generation) if file_generation != generation)
+        };
+        if other {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,268 @@
+//! Change notifications of a [`RollbackMap`].
+//!
+//! Observers registered by [`RollbackMap::subscribe`] are called synchronously
+//! with an [`Event`] after every change of the map. With the `async` feature enabled,
+//! [`RollbackMap::stream`] delivers the events as a `futures::Stream` instead.
+//!
+//! Observers are not cloned with the map.
+use crate::rollbackmap::RollbackMap;
+use core::fmt;
+
+#[cfg(feature = "async")]
+use core::pin::Pin;
+#[cfg(feature = "async")]
+use core::task::{Context, Poll};
+#[cfg(feature = "async")]
+use futures::channel::mpsc::{self, UnboundedReceiver};
+#[cfg(feature = "async")]
+use futures::stream::{FusedStream, Stream};
+
+/// A change of a [`RollbackMap`].
+///
+/// Observers receive events with borrowed keys, `Event<&K>`,
+/// use [`Event::cloned`] to keep them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event<K> {
+    /// A key that was not present was inserted.
+    Inserted(K),
+    /// The value of a present key was replaced, or borrowed mutably.
+    Updated(K),
+    /// A present key was removed.
+    Removed(K),
+    /// The map was cleared.
+    Cleared,
+    /// A checkpoint was created.
+    CheckpointCreated(u32),
+    /// The checkpoints expired to fit the memory budget, see [`crate::budget`].
+    CheckpointsExpired(Vec<u32>),
+    /// The map was rolled back to the checkpoint.
+    RolledBack {
+        /// Checkpoint the map was rolled back to.
+        to: u32,
+        /// Keys whose values may differ after the rollback, sorted.
+        reverted_keys: Vec<K>,
+    },
+}
+
+impl<K> Event<K> {
+    // Makes an event borrowing the owned keys.
+    pub(crate) fn as_ref(&self) -> Event<&K> {
+        match self {
+            Event::Inserted(key) => Event::Inserted(key),
+            Event::Updated(key) => Event::Updated(key),
+            Event::Removed(key) => Event::Removed(key),
+            Event::Cleared => Event::Cleared,
+            Event::CheckpointCreated(checkpoint) => Event::CheckpointCreated(*checkpoint),
+            Event::CheckpointsExpired(checkpoints) => {
+                Event::CheckpointsExpired(checkpoints.clone())
+            }
+            Event::RolledBack { to, reverted_keys } => Event::RolledBack {
+                to: *to,
+                reverted_keys: reverted_keys.iter().collect(),
+            },
+        }
+    }
+}
+
+impl<K: Clone> Event<&K> {
+    /// Makes an event with owned keys by cloning them.
+    pub fn cloned(&self) -> Event<K> {
+        match self {
+            Event::Inserted(key) => Event::Inserted((*key).clone()),
+            Event::Updated(key) => Event::Updated((*key).clone()),
+            Event::Removed(key) => Event::Removed((*key).clone()),
+            Event::Cleared => Event::Cleared,
+            Event::CheckpointCreated(checkpoint) => Event::CheckpointCreated(*checkpoint),
+            Event::CheckpointsExpired(checkpoints) => {
+                Event::CheckpointsExpired(checkpoints.clone())
+            }
+            Event::RolledBack { to, reverted_keys } => Event::RolledBack {
+                to: *to,
+                reverted_keys: reverted_keys.iter().map(|key| (*key).clone()).collect(),
+            },
+        }
+    }
+}
+
+/// Identifies an observer registered by [`RollbackMap::subscribe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Subscription(u64);
+
+// An observer returns false to be unsubscribed.
+type Observer<K> = Box<dyn FnMut(&Event<&K>) -> bool + Send + Sync>;
+
+// Observers of a map.
+pub(crate) struct Observers<K> {
+    observers: Vec<(Subscription, Observer<K>)>,
+    next_id: u64,
+}
+
+impl<K> Observers<K> {
+    pub(crate) fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    pub(crate) fn notify(&mut self, event: &Event<&K>) {
+        let mut index = 0;
+        while index < self.observers.len() {
+            if (self.observers[index].1)(event) {
+                index += 1;
+            } else {
+                drop(self.observers.remove(index));
+            }
+        }
+    }
+
+    fn add(&mut self, observer: Observer<K>) -> Subscription {
+        let subscription = Subscription(self.next_id);
+        self.next_id += 1;
+        self.observers.push((subscription, observer));
+        subscription
+    }
+
+    fn remove(&mut self, subscription: Subscription) -> bool {
+        let count = self.observers.len();
+        self.observers.retain(|(id, _)| *id != subscription);
+        self.observers.len() != count
+    }
+}
+
+impl<K> Default for Observers<K> {
+    fn default() -> Self {
+        Observers {
+            observers: Vec::new(),
+            next_id: 0,
+        }
+    }
+}
+
+impl<K> Clone for Observers<K> {
+    // The observers are subscribed to the original map only.
+    fn clone(&self) -> Self {
+        Observers::default()
+    }
+}
+
+impl<K> fmt::Debug for Observers<K> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Observers")
+            .field("count", &self.observers.len())
+            .finish()
+    }
+}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Registers an observer called after every change of the map.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::events::Event;
+    /// use rollbackmap::RollbackMap;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// let received = Arc::new(Mutex::new(Vec::new()));
+    /// let mut map = RollbackMap::new();
+    /// let sink = Arc::clone(&received);
+    /// map.subscribe(move |event: &Event<&u32>| sink.lock().unwrap().push(event.cloned()));
+    ///
+    /// map.insert(1, "a");
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "b");
+    /// map.insert(2, "c");
+    /// map.rollback(checkpoint);
+    ///
+    /// assert_eq!(
+    ///     *received.lock().unwrap(),
+    ///     vec![
+    ///         Event::Inserted(1),
+    ///         Event::CheckpointCreated(checkpoint),
+    ///         Event::Updated(1),
+    ///         Event::Inserted(2),
+    ///         Event::RolledBack { to: checkpoint, reverted_keys: vec![1, 2] },
+    ///     ]
+    /// );
+    /// ```
+    pub fn subscribe<F>(&mut self, mut observer: F) -> Subscription
+    where
+        F: FnMut(&Event<&K>) + Send + Sync + 'static,
+    {
+        self.observers.add(Box::new(move |event| {
+            observer(event);
+            true
+        }))
+    }
+
+    /// Unregisters the observer.
+    /// Returns `false` if it was not registered.
+    pub fn unsubscribe(&mut self, subscription: Subscription) -> bool {
+        self.observers.remove(subscription)
+    }
+
+    /// Returns a stream of the map changes.
+    ///
+    /// The events are buffered until they are polled.
+    /// Dropping the stream unsubscribes it at the next change of the map.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use futures::executor::block_on;
+    /// use futures::StreamExt;
+    /// use rollbackmap::events::Event;
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// let mut events = map.stream();
+    /// map.insert(1, "a");
+    /// map.remove(&1);
+    /// assert_eq!(block_on(events.next()), Some(Event::Inserted(1)));
+    /// assert_eq!(block_on(events.next()), Some(Event::Removed(1)));
+    /// drop(map);
+    /// assert_eq!(block_on(events.next()), None);
+    /// ```
+    #[cfg(feature = "async")]
+    pub fn stream(&mut self) -> EventStream<K>
+    where
+        K: Clone + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::unbounded();
+        self.observers.add(Box::new(move |event: &Event<&K>| {
+            sender.unbounded_send(event.cloned()).is_ok()
+        }));
+        EventStream { receiver }
+    }
+}
+
+/// A stream of the changes of a [`RollbackMap`].
+///
+/// See [`RollbackMap::stream`]. The stream ends when the map is dropped.
+#[cfg(feature = "async")]
+#[derive(Debug)]
+pub struct EventStream<K> {
+    receiver: UnboundedReceiver<Event<K>>,
+}
+
+#[cfg(feature = "async")]
+impl<K> Stream for EventStream<K> {
+    type Item = Event<K>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Event<K>>> {
+        Pin::new(&mut self.receiver).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.receiver.size_hint()
+    }
+}
+
+#[cfg(feature = "async")]
+impl<K> FusedStream for EventStream<K> {
+    fn is_terminated(&self) -> bool {
+        self.receiver.is_terminated()
+    }
+}
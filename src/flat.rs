@@ -0,0 +1,54 @@
+//! Serialization of the visible content of a [`RollbackMap`] only.
+//!
+//! The map is written as a plain map of its visible key-value pairs, checkpoints are dropped.
+//! Deserialization creates a map without checkpoints.
+//!
+//! # Examples
+//!
+//! Basic usage:
+//!
+//! ```
+//! use rollbackmap::RollbackMap;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct Session {
+//!     #[serde(with = "rollbackmap::flat")]
+//!     scores: RollbackMap<String, u32>,
+//! }
+//!
+//! let mut session = Session { scores: RollbackMap::new() };
+//! session.scores.insert("a".to_owned(), 1);
+//! session.scores.checkpoint();
+//! session.scores.insert("b".to_owned(), 2);
+//!
+//! let json = serde_json::to_string(&session).unwrap();
+//! assert_eq!(json, r#"{"scores":{"a":1,"b":2}}"#);
+//! let restored: Session = serde_json::from_str(&json).unwrap();
+//! assert_eq!(restored.scores, session.scores);
+//! assert_eq!(restored.scores.get_checkpoints_count(), 0);
+//! ```
+use crate::RollbackMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+
+/// Serializes the visible key-value pairs of the map.
+pub fn serialize<K, V, S>(map: &RollbackMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    K: Ord + Serialize,
+    V: Serialize,
+    S: Serializer,
+{
+    serializer.collect_map(map.iter())
+}
+
+/// Deserializes a map of key-value pairs into a `RollbackMap` without checkpoints.
+pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<RollbackMap<K, V>, D::Error>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    let entries = BTreeMap::<K, V>::deserialize(deserializer)?;
+    Ok(entries.into_iter().collect())
+}
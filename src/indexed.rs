@@ -0,0 +1,304 @@
+//! Rollback map finding the layer of a key through an index.
+//!
+//! A lookup in a [`RollbackMap`] probes the layers from the newest one, so its cost
+//! grows with the number of checkpoints. [`IndexedRollbackMap`] maintains an index
+//! from every key to the newest layer that inserted or removed it, so that `get`,
+//! `contains_key` and the lookup of the previous value by `insert` probe a single layer.
+//!
+//! The index is updated by the insertions and removals, repaired from the layers
+//! dropped by a rollback, and rebuilt when the layers are folded together
+//! by [`RollbackMap::prune`] or by the memory budget.
+use crate::rollbackmap::{base_index, get_key_value, Previous, RollbackMap, VersionState};
+use core::borrow::Borrow;
+use core::ops::Deref;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A [`RollbackMap`] with an index from the keys to their layers.
+///
+/// The keys are cloned into the index. Read access to the wrapped map is available
+/// through `Deref`, `get` and `contains_key` use the index.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::indexed::IndexedRollbackMap;
+///
+/// let mut map = IndexedRollbackMap::new();
+/// map.insert(1, "a");
+/// for checkpoint in 0..100 {
+///     map.insert(checkpoint + 2, "b");
+///     map.checkpoint();
+/// }
+/// assert_eq!(map.insert(1, "c"), Some("a"));
+/// assert!(map.rollback(0));
+/// assert_eq!(map.get(&1), Some(&"a"));
+/// assert_eq!(map.get(&3), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct IndexedRollbackMap<K: Ord, V> {
+    map: RollbackMap<K, V>,
+    // Index of the newest version that has the key in its data or its removed keys.
+    index: BTreeMap<K, usize>,
+    // Index of the oldest visible version.
+    base: usize,
+}
+
+impl<K: Ord + Clone, V> Default for IndexedRollbackMap<K, V> {
+    /// Creates an empty `IndexedRollbackMap`.
+    fn default() -> Self {
+        IndexedRollbackMap::new()
+    }
+}
+
+impl<K: Ord + Clone, V> IndexedRollbackMap<K, V> {
+    /// Makes a new, empty `IndexedRollbackMap`.
+    pub fn new() -> Self {
+        Self::from_map(RollbackMap::new())
+    }
+
+    /// Wraps the map, indexing the keys of all its layers right away.
+    pub fn from_map(map: RollbackMap<K, V>) -> Self {
+        let mut map = IndexedRollbackMap {
+            map,
+            index: BTreeMap::new(),
+            base: 0,
+        };
+        map.rebuild();
+        map
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    /// See [`RollbackMap::contains_key`].
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    /// See [`RollbackMap::get`].
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        locate(&self.map.versions, &self.index, self.base, key).map(|(_, value)| value)
+    }
+
+    /// Inserts a key-value pair into the map.
+    /// See [`RollbackMap::insert`].
+    pub fn insert(&mut self, key: K, value: V) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.replace(key, value).map(Previous::into_owned)
+    }
+
+    /// Inserts a key-value pair into the map without cloning the old value.
+    /// See [`RollbackMap::replace`].
+    pub fn replace(&mut self, key: K, value: V) -> Option<Previous<'_, V>> {
+        let current = self.map.versions.len() - 1;
+        let (index, base) = (&mut self.index, self.base);
+        match index.get_mut(&key) {
+            Some(layer) => {
+                let found = *layer;
+                *layer = current;
+                self.map.replace_with(key, value, |older, key| {
+                    locate_at(older, Some(found), base, key)
+                })
+            }
+            // None of the versions has the key
+            None => {
+                index.insert(key.clone(), current);
+                self.map
+                    .replace_with(key, value, |_: &[VersionState<K, V>], _: &K| None)
+            }
+        }
+    }
+
+    /// Removes a key from the map.
+    /// See [`RollbackMap::remove`].
+    ///
+    /// Removing a key changed since the last checkpoint probes the older layers
+    /// to find the layer that has it then.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        let current = self.map.versions.len() - 1;
+        let found = *self.index.get(key)?;
+        if found < self.base {
+            return None;
+        }
+        let base = self.base;
+        let removed = if found == current {
+            // A value kept by a checkpoint has to be hidden by the removal
+            self.map.take_with(key, get_key_value)
+        } else {
+            self.map
+                .take_with(key, |older, key| locate_at(older, Some(found), base, key))
+        };
+        let removed = removed.map(Previous::into_owned);
+
+        let last = &self.map.versions[current];
+        if last.data.contains_key(key) || last.removed_keys.contains(key) {
+            if let Some(layer) = self.index.get_mut(key) {
+                *layer = current;
+            }
+        } else if found == current {
+            // The key is not touched by the current version anymore
+            self.repair(key);
+        }
+        removed
+    }
+
+    /// Clears the map.
+    /// See [`RollbackMap::clear`].
+    pub fn clear(&mut self) {
+        let current = self.map.versions.len() - 1;
+        let last = &self.map.versions[current];
+        let keys: Vec<K> = last
+            .data
+            .keys()
+            .chain(last.removed_keys.iter())
+            .cloned()
+            .collect();
+        self.map.clear();
+        self.base = current;
+        // A rollback over the clear looks the keys up in the older versions again
+        for key in keys {
+            self.repair(&key);
+        }
+    }
+
+    /// Creates a checkpoint.
+    /// See [`RollbackMap::checkpoint`].
+    pub fn checkpoint(&mut self) -> Option<u32> {
+        let count = self.map.versions.len();
+        let checkpoint = self.map.checkpoint();
+        // The memory budget folds the older versions together
+        if self.map.versions.len() != count + 1 {
+            self.rebuild();
+        }
+        checkpoint
+    }
+
+    /// Rollbacks to saved checkpoint, repairing the index of the keys
+    /// of the dropped layers.
+    /// See [`RollbackMap::rollback`].
+    pub fn rollback(&mut self, checkpoint: u32) -> bool {
+        let kept = match self.map.rollback_index(checkpoint) {
+            Some(index) => index,
+            None => return false,
+        };
+        let mut keys = BTreeSet::new();
+        for version in &self.map.versions[kept + 1..] {
+            keys.extend(version.data.keys().cloned());
+            keys.extend(version.removed_keys.iter().cloned());
+        }
+        let rollback = self.map.rollback(checkpoint);
+        self.base = base_index(&self.map.versions);
+        for key in keys {
+            self.repair(&key);
+        }
+        rollback
+    }
+
+    /// Deletes all the checkpoints except the last one, rebuilding the index.
+    /// See [`RollbackMap::prune`].
+    pub fn prune(&mut self) -> Option<u32> {
+        let checkpoint = self.map.prune();
+        self.rebuild();
+        checkpoint
+    }
+
+    /// Returns the wrapped map, dropping the index.
+    pub fn into_inner(self) -> RollbackMap<K, V> {
+        self.map
+    }
+
+    // Looks the newest version that has the key up in the older versions.
+    fn repair<Q>(&mut self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        let versions = &self.map.versions;
+        let found = versions.iter().rposition(|version| {
+            version.data.contains_key(key) || version.removed_keys.contains(key)
+        });
+        match found {
+            Some(layer) => {
+                if let Some(entry) = self.index.get_mut(key) {
+                    *entry = layer;
+                } else {
+                    let version = &versions[layer];
+                    let found_key = match version.data.get_key_value(key) {
+                        Some((found_key, _)) => found_key,
+                        None => version.removed_keys.get(key).expect("the key is removed"),
+                    };
+                    self.index.insert(found_key.clone(), layer);
+                }
+            }
+            None => {
+                self.index.remove(key);
+            }
+        }
+    }
+
+    fn rebuild(&mut self) {
+        self.index.clear();
+        for (layer, version) in self.map.versions.iter().enumerate() {
+            for key in version.data.keys().chain(version.removed_keys.iter()) {
+                self.index.insert(key.clone(), layer);
+            }
+        }
+        self.base = base_index(&self.map.versions);
+    }
+}
+
+impl<K: Ord, V> Deref for IndexedRollbackMap<K, V> {
+    type Target = RollbackMap<K, V>;
+
+    fn deref(&self) -> &RollbackMap<K, V> {
+        &self.map
+    }
+}
+
+// Looks the key up in the version the index points to.
+fn locate<'a, K, V, Q>(
+    versions: &'a [VersionState<K, V>],
+    index: &BTreeMap<K, usize>,
+    base: usize,
+    key: &Q,
+) -> Option<(&'a K, &'a V)>
+where
+    K: Borrow<Q> + Ord,
+    Q: Ord + ?Sized,
+{
+    locate_at(versions, index.get(key).copied(), base, key)
+}
+
+// A version older than the base one is hidden by a clear,
+// and a removed key is not in the data of its version.
+fn locate_at<'a, K, V, Q>(
+    versions: &'a [VersionState<K, V>],
+    layer: Option<usize>,
+    base: usize,
+    key: &Q,
+) -> Option<(&'a K, &'a V)>
+where
+    K: Borrow<Q> + Ord,
+    Q: Ord + ?Sized,
+{
+    match layer {
+        Some(layer) if layer >= base => versions.get(layer)?.data.get_key_value(key),
+        _ => None,
+    }
+}
@@ -0,0 +1,522 @@
+//! Write-ahead journal of [`RollbackMap`] mutations.
+//!
+//! [`JournaledRollbackMap`] appends every mutation and checkpoint event to a writer
+//! before applying it, and [`RollbackMap::replay`] rebuilds the exact same map,
+//! checkpoints included, from the written journal.
+//!
+//! All integers are little-endian. The journal starts with [`MAGIC`] followed by the
+//! `u16` [`FORMAT_VERSION`], then the records follow. Each record is framed as:
+//!
+//! | Field    | Size     | Description                           |
+//! |----------|----------|---------------------------------------|
+//! | length   | 4        | length of the payload                 |
+//! | payload  | variable | record tag (1 byte) and its fields    |
+//! | checksum | 4        | CRC-32 (IEEE) of the payload          |
+//!
+//! | Tag | Record     | Fields                                 |
+//! |-----|------------|----------------------------------------|
+//! | 1   | insert     | key, value                             |
+//! | 2   | remove     | key                                    |
+//! | 3   | clear      |                                        |
+//! | 4   | checkpoint | `u32` created checkpoint               |
+//! | 5   | rollback   | `u32` checkpoint to rollback to        |
+//! | 6   | prune      |                                        |
+//! | 7   | prune      | `u32` count, `u32` pinned checkpoints  |
+//!
+//! A prune is journaled with the checkpoints pinned at the time, if any,
+//! so that the replayed map keeps their layers as well. The pins themselves
+//! are not journaled, the kept layers are folded by the next prune after replay.
+use crate::codec::{invalid_data, read_u32, read_u8, write_u32, Codec, Crc32, LittleEndian};
+use crate::rollbackmap::RollbackMap;
+use core::borrow::Borrow;
+use core::ops::Deref;
+use std::io::{self, Read, Write};
+
+/// Magic bytes every journal starts with.
+pub const MAGIC: [u8; 4] = *b"RBJL";
+
+/// Version of the journal format written by this crate.
+///
+/// Journals of this or any older format version can be replayed.
+pub const FORMAT_VERSION: u16 = 1;
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+const CLEAR: u8 = 3;
+const CHECKPOINT: u8 = 4;
+const ROLLBACK: u8 = 5;
+const PRUNE: u8 = 6;
+const PRUNE_PINNED: u8 = 7;
+
+/// A single journal record.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Record<K, V> {
+    Insert(K, V),
+    Remove(K),
+    Clear,
+    Checkpoint(u32),
+    Rollback(u32),
+    // The checkpoints pinned when the map was pruned.
+    Prune(Vec<u32>),
+}
+
+impl<K, V> Record<K, V> {
+    // Checkpoint events change the set of checkpoints the map can be restored to.
+    pub(crate) fn is_checkpoint_event(&self) -> bool {
+        matches!(
+            self,
+            Record::Checkpoint(_) | Record::Rollback(_) | Record::Prune(_)
+        )
+    }
+}
+
+pub(crate) fn write_header(writer: &mut dyn Write) -> io::Result<()> {
+    writer.write_all(&MAGIC)?;
+    LittleEndian.encode(&FORMAT_VERSION, writer)
+}
+
+pub(crate) fn read_header(reader: &mut dyn Read) -> io::Result<()> {
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+    if magic != MAGIC {
+        return Err(invalid_data("not a RollbackMap journal"));
+    }
+    let format_version: u16 = LittleEndian.decode(reader)?;
+    if format_version == 0 || format_version > FORMAT_VERSION {
+        return Err(invalid_data("unsupported journal format version"));
+    }
+    Ok(())
+}
+
+// Encodes the frame of a record built from borrowed parts,
+// so the map does not have to clone them.
+fn encode_record<K, V, KC, VC>(
+    record: &Record<&K, &V>,
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<Vec<u8>>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    let mut payload = Vec::new();
+    match record {
+        Record::Insert(key, value) => {
+            payload.push(INSERT);
+            key_codec.encode(key, &mut payload)?;
+            value_codec.encode(value, &mut payload)?;
+        }
+        Record::Remove(key) => {
+            payload.push(REMOVE);
+            key_codec.encode(key, &mut payload)?;
+        }
+        Record::Clear => payload.push(CLEAR),
+        Record::Checkpoint(checkpoint) => {
+            payload.push(CHECKPOINT);
+            write_u32(&mut payload, *checkpoint)?;
+        }
+        Record::Rollback(checkpoint) => {
+            payload.push(ROLLBACK);
+            write_u32(&mut payload, *checkpoint)?;
+        }
+        Record::Prune(pinned) if pinned.is_empty() => payload.push(PRUNE),
+        Record::Prune(pinned) => {
+            payload.push(PRUNE_PINNED);
+            write_u32(&mut payload, pinned.len() as u32)?;
+            for &checkpoint in pinned {
+                write_u32(&mut payload, checkpoint)?;
+            }
+        }
+    }
+
+    let mut crc = Crc32::new();
+    crc.update(&payload);
+    let mut frame = Vec::with_capacity(payload.len() + 8);
+    write_u32(&mut frame, payload.len() as u32)?;
+    frame.extend_from_slice(&payload);
+    write_u32(&mut frame, crc.finish())?;
+    Ok(frame)
+}
+
+/// Reads the next record, `None` is returned at the end of the journal.
+///
+/// Returns an `UnexpectedEof` error for a partially written record
+/// and an `InvalidData` error for a corrupted one.
+pub(crate) fn read_record<K, V, KC, VC>(
+    reader: &mut dyn Read,
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<Option<Record<K, V>>>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    match read_frame(reader)? {
+        Some(payload) => decode_record(&payload, key_codec, value_codec).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Reads the payload of the next record and verifies its checksum.
+pub(crate) fn read_frame(reader: &mut dyn Read) -> io::Result<Option<Vec<u8>>> {
+    let mut length = [0u8; 4];
+    let mut read = 0;
+    while read < length.len() {
+        match reader.read(&mut length[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => read += n,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    let length = u32::from_le_bytes(length) as usize;
+
+    let mut payload = Vec::new();
+    reader.take(length as u64).read_to_end(&mut payload)?;
+    if payload.len() != length {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let checksum = read_u32(reader)?;
+    let mut crc = Crc32::new();
+    crc.update(&payload);
+    if crc.finish() != checksum {
+        return Err(invalid_data("journal record checksum mismatch"));
+    }
+    Ok(Some(payload))
+}
+
+pub(crate) fn decode_record<K, V, KC, VC>(
+    payload: &[u8],
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<Record<K, V>>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    let mut payload = payload;
+    let record = match read_u8(&mut payload)? {
+        INSERT => {
+            let key = key_codec.decode(&mut payload)?;
+            Record::Insert(key, value_codec.decode(&mut payload)?)
+        }
+        REMOVE => Record::Remove(key_codec.decode(&mut payload)?),
+        CLEAR => Record::Clear,
+        CHECKPOINT => Record::Checkpoint(read_u32(&mut payload)?),
+        ROLLBACK => Record::Rollback(read_u32(&mut payload)?),
+        PRUNE => Record::Prune(Vec::new()),
+        PRUNE_PINNED => {
+            let count = read_u32(&mut payload)?;
+            let mut pinned = Vec::new();
+            for _ in 0..count {
+                pinned.push(read_u32(&mut payload)?);
+            }
+            Record::Prune(pinned)
+        }
+        _ => return Err(invalid_data("unknown journal record")),
+    };
+    if !payload.is_empty() {
+        return Err(invalid_data("journal record has trailing bytes"));
+    }
+    Ok(record)
+}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Rebuilds the map from a journal written by [`JournaledRollbackMap`].
+    ///
+    /// The replayed map has the same content and the same checkpoints
+    /// as the journaled one had after the last written record.
+    ///
+    /// Returns an error of the `InvalidData` kind if the journal is corrupted
+    /// or does not describe a valid sequence of operations,
+    /// and of the `UnexpectedEof` kind if the last record is incomplete.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::codec::LittleEndian;
+    /// use rollbackmap::journal::JournaledRollbackMap;
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = JournaledRollbackMap::new(Vec::new(), LittleEndian, LittleEndian).unwrap();
+    /// map.insert(1u32, 10u32).unwrap();
+    /// map.checkpoint().unwrap();
+    /// map.remove(&1).unwrap();
+    ///
+    /// let (map, journal) = map.into_parts();
+    /// let replayed = RollbackMap::replay(journal.as_slice(), &LittleEndian, &LittleEndian).unwrap();
+    /// assert!(replayed.history_eq(&map));
+    /// ```
+    pub fn replay<R, KC, VC>(reader: R, key_codec: &KC, value_codec: &VC) -> io::Result<Self>
+    where
+        R: Read,
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        let mut reader = reader;
+        read_header(&mut reader)?;
+        let mut map = RollbackMap::new();
+        while let Some(record) = read_record(&mut reader, key_codec, value_codec)? {
+            map.apply(record)?;
+        }
+        Ok(map)
+    }
+
+    // Applies the replayed record, checking that the checkpoint events match.
+    pub(crate) fn apply(&mut self, record: Record<K, V>) -> io::Result<()> {
+        match record {
+            Record::Insert(key, value) => {
+                self.replace(key, value);
+            }
+            Record::Remove(key) => {
+                self.remove_key(key);
+            }
+            Record::Clear => self.clear(),
+            Record::Checkpoint(checkpoint) => {
+                if self.checkpoint() != Some(checkpoint) {
+                    return Err(invalid_data("journal checkpoint does not match"));
+                }
+            }
+            Record::Rollback(checkpoint) => {
+                if !self.rollback(checkpoint) {
+                    return Err(invalid_data("journal rollback to unknown checkpoint"));
+                }
+            }
+            Record::Prune(pinned) => {
+                self.prune_pinned(&pinned);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A [`RollbackMap`] that journals its mutations to a writer.
+///
+/// Every record is written before the operation is applied to the map,
+/// so a failed write leaves the map unchanged. It may leave a partial record
+/// at the end of the journal though, so the journal is poisoned then:
+/// every later operation returns an error of the same kind, and nothing
+/// more is written after the partial record.
+/// Operations that do not change the map, e.g. removing a missing key
+/// or rolling back to an unknown checkpoint, are not journaled.
+///
+/// Read access to the map is available through `Deref`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::codec::{LengthPrefixed, LittleEndian};
+/// use rollbackmap::journal::JournaledRollbackMap;
+/// use rollbackmap::RollbackMap;
+///
+/// let mut map = JournaledRollbackMap::new(Vec::new(), LittleEndian, LengthPrefixed).unwrap();
+/// map.insert(1u32, "a".to_owned()).unwrap();
+/// let checkpoint = map.checkpoint().unwrap().unwrap();
+/// map.clear().unwrap();
+/// assert!(map.is_empty());
+/// assert!(map.rollback(checkpoint).unwrap());
+/// assert_eq!(map.get(&1), Some(&"a".to_owned()));
+///
+/// let journal = map.into_parts().1;
+/// let replayed: RollbackMap<u32, String> =
+///     RollbackMap::replay(journal.as_slice(), &LittleEndian, &LengthPrefixed).unwrap();
+/// assert_eq!(replayed.get(&1), Some(&"a".to_owned()));
+/// ```
+#[derive(Debug)]
+pub struct JournaledRollbackMap<K: Ord, V, W, KC, VC> {
+    pub(crate) map: RollbackMap<K, V>,
+    pub(crate) writer: W,
+    pub(crate) key_codec: KC,
+    pub(crate) value_codec: VC,
+    // The kind of the write error that poisoned the journal.
+    pub(crate) poisoned: Option<io::ErrorKind>,
+}
+
+impl<K, V, W, KC, VC> JournaledRollbackMap<K, V, W, KC, VC>
+where
+    K: Ord,
+    W: Write,
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    /// Makes a new, empty map and starts the journal by writing its header.
+    pub fn new(writer: W, key_codec: KC, value_codec: VC) -> io::Result<Self> {
+        let mut writer = writer;
+        write_header(&mut writer)?;
+        Ok(JournaledRollbackMap::resume(
+            RollbackMap::new(),
+            writer,
+            key_codec,
+            value_codec,
+        ))
+    }
+
+    // Continues the journal of a replayed map.
+    pub(crate) fn resume(
+        map: RollbackMap<K, V>,
+        writer: W,
+        key_codec: KC,
+        value_codec: VC,
+    ) -> Self {
+        JournaledRollbackMap {
+            map,
+            writer,
+            key_codec,
+            value_codec,
+            poisoned: None,
+        }
+    }
+
+    /// Journals and inserts a key-value pair into the map.
+    /// See [`RollbackMap::insert`].
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<V>>
+    where
+        V: Clone,
+    {
+        self.write(&Record::Insert(&key, &value))?;
+        Ok(self.map.insert(key, value))
+    }
+
+    /// Journals and removes a key from the map.
+    /// See [`RollbackMap::remove`].
+    pub fn remove<Q>(&mut self, key: &Q) -> io::Result<Option<V>>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        match self.map.deep_get_key_value(key) {
+            Some((found_key, _)) => {
+                write_checked(
+                    &mut self.writer,
+                    &mut self.poisoned,
+                    &Record::Remove(found_key),
+                    &self.key_codec,
+                    &self.value_codec,
+                )?;
+            }
+            None => return Ok(None),
+        }
+        Ok(self.map.remove(key))
+    }
+
+    /// Journals and clears the map.
+    /// See [`RollbackMap::clear`].
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.write(&Record::Clear)?;
+        self.map.clear();
+        Ok(())
+    }
+
+    /// Journals and creates a checkpoint.
+    /// See [`RollbackMap::checkpoint`].
+    pub fn checkpoint(&mut self) -> io::Result<Option<u32>> {
+        if let Some(last) = self.map.versions.last() {
+            self.write(&Record::Checkpoint(last.checkpoint))?;
+        }
+        Ok(self.map.checkpoint())
+    }
+
+    /// Journals and rollbacks to the checkpoint.
+    /// See [`RollbackMap::rollback`].
+    pub fn rollback(&mut self, checkpoint: u32) -> io::Result<bool> {
+        if self.map.rollback_index(checkpoint).is_none() {
+            return Ok(false);
+        }
+        self.write(&Record::Rollback(checkpoint))?;
+        Ok(self.map.rollback(checkpoint))
+    }
+
+    /// Journals and deletes all the checkpoints except the last one.
+    /// See [`RollbackMap::prune`].
+    ///
+    /// The pinned checkpoints are journaled with the prune,
+    /// so the replayed map keeps their layers too.
+    pub fn prune(&mut self) -> io::Result<Option<u32>> {
+        self.write(&Record::Prune(self.map.pins.pinned()))?;
+        Ok(self.map.prune())
+    }
+
+    /// Flushes the journal writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        check_poisoned(self.poisoned)?;
+        self.writer.flush()
+    }
+
+    /// Returns `true` if a failed write poisoned the journal.
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.is_some()
+    }
+
+    /// Returns a reference to the journal writer.
+    pub fn writer(&self) -> &W {
+        &self.writer
+    }
+
+    /// Returns a mutable reference to the journal writer.
+    ///
+    /// Writing to it directly corrupts the journal.
+    pub fn writer_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    /// Returns the map and the journal writer.
+    pub fn into_parts(self) -> (RollbackMap<K, V>, W) {
+        (self.map, self.writer)
+    }
+
+    fn write(&mut self, record: &Record<&K, &V>) -> io::Result<()> {
+        write_checked(
+            &mut self.writer,
+            &mut self.poisoned,
+            record,
+            &self.key_codec,
+            &self.value_codec,
+        )
+    }
+}
+
+// Writes the record unless the journal is poisoned, a failed write poisons it.
+// A record that cannot be encoded is not written at all, so it does not poison the journal.
+fn write_checked<K, V, KC, VC>(
+    writer: &mut dyn Write,
+    poisoned: &mut Option<io::ErrorKind>,
+    record: &Record<&K, &V>,
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<()>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    check_poisoned(*poisoned)?;
+    let frame = encode_record(record, key_codec, value_codec)?;
+    let result = writer.write_all(&frame);
+    if let Err(error) = &result {
+        *poisoned = Some(error.kind());
+    }
+    result
+}
+
+fn check_poisoned(poisoned: Option<io::ErrorKind>) -> io::Result<()> {
+    match poisoned {
+        Some(kind) => Err(io::Error::new(
+            kind,
+            "journal is poisoned by a failed write",
+        )),
+        None => Ok(()),
+    }
+}
+
+impl<K: Ord, V, W, KC, VC> Deref for JournaledRollbackMap<K, V, W, KC, VC> {
+    type Target = RollbackMap<K, V>;
+
+    fn deref(&self) -> &RollbackMap<K, V> {
+        &self.map
+    }
+}
@@ -9,8 +9,29 @@
     unused_qualifications
 )]
 
+pub mod batch;
+pub mod bloom;
+pub mod budget;
+pub mod codec;
+pub mod dump;
+pub mod durable;
+pub mod events;
+#[cfg(feature = "serde")]
+pub mod flat;
+pub mod indexed;
+pub mod journal;
+pub mod optimistic;
+pub mod persistent;
 mod rollbackmap;
-pub use crate::rollbackmap::RollbackMap;
+pub mod snapshot;
+pub mod spill;
+pub mod stats;
+pub mod sync;
+pub mod validate;
+pub use crate::persistent::PersistentRollbackMap;
+pub use crate::rollbackmap::{
+    Change, IntoIter, Iter, PinGuard, Previous, RollbackMap, SharedRollbackMap,
+};
 
 #[cfg(test)]
 mod tests;
@@ -0,0 +1,311 @@
+//! Optimistic transactions over a shared [`RollbackMap`].
+//!
+//! [`OptimisticRollbackMap`] is a handle to a map shared between threads.
+//! Every thread runs its own [`Transaction`]: reads go to the map and are recorded
+//! in the read set, writes are buffered in the write set. Nothing is locked
+//! while a transaction runs. At commit time it is validated that no transaction
+//! committed since it began wrote a key it read; otherwise [`Conflict`] is returned,
+//! the writes are discarded, and the caller can retry with a new transaction.
+//!
+//! Reads of missing keys are recorded too, so a concurrent insertion of such a key
+//! is a conflict as well. Rollbacks and clears of the shared map conflict
+//! with every transaction that began before them.
+use crate::rollbackmap::RollbackMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Error returned by [`Transaction::commit`] when a key read by the transaction
+/// was written by another transaction committed in the meantime.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Conflict;
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("the transaction conflicts with a committed one")
+    }
+}
+
+impl Error for Conflict {}
+
+/// A [`RollbackMap`] shared between threads that run optimistic transactions.
+///
+/// Cloning the handle shares the same map.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::optimistic::{Conflict, OptimisticRollbackMap};
+/// use rollbackmap::RollbackMap;
+///
+/// let map = OptimisticRollbackMap::new(RollbackMap::new());
+/// let mut first = map.begin();
+/// let mut second = map.begin();
+///
+/// let count = first.get(&"count").unwrap_or(0);
+/// first.insert("count", count + 1);
+/// let count = second.get(&"count").unwrap_or(0);
+/// second.insert("count", count + 1);
+///
+/// assert_eq!(first.commit(), Ok(()));
+/// assert_eq!(second.commit(), Err(Conflict));
+/// assert_eq!(map.get(&"count"), Some(1));
+/// ```
+#[derive(Debug)]
+pub struct OptimisticRollbackMap<K: Ord, V> {
+    shared: Arc<Mutex<Shared<K, V>>>,
+}
+
+impl<K: Ord, V> Clone for OptimisticRollbackMap<K, V> {
+    fn clone(&self) -> Self {
+        OptimisticRollbackMap {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Shared<K: Ord, V> {
+    map: RollbackMap<K, V>,
+    // Sequence number of the last commit.
+    sequence: u64,
+    // Commits the active transactions may conflict with, from the oldest one.
+    log: VecDeque<Commit<K>>,
+    // Sequence numbers the active transactions began at, with their count.
+    active: BTreeMap<u64, usize>,
+}
+
+#[derive(Debug)]
+struct Commit<K> {
+    sequence: u64,
+    // `None` if the whole map was changed by a rollback or a clear.
+    keys: Option<BTreeSet<K>>,
+}
+
+impl<K: Ord, V> Shared<K, V> {
+    fn commit(&mut self, keys: Option<BTreeSet<K>>) {
+        self.sequence += 1;
+        if !self.active.is_empty() {
+            self.log.push_back(Commit {
+                sequence: self.sequence,
+                keys,
+            });
+        }
+    }
+
+    fn conflicts(&self, start: u64, reads: &BTreeSet<K>) -> bool {
+        self.log
+            .iter()
+            .filter(|commit| commit.sequence > start)
+            .any(|commit| match &commit.keys {
+                Some(keys) => reads.iter().any(|key| keys.contains(key)),
+                None => true,
+            })
+    }
+
+    fn end(&mut self, start: u64) {
+        if let Some(count) = self.active.get_mut(&start) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(&start);
+            }
+        }
+        // Only the commits newer than the oldest active transaction can conflict.
+        match self.active.keys().next() {
+            Some(&oldest) => {
+                while let Some(commit) = self.log.front() {
+                    if commit.sequence > oldest {
+                        break;
+                    }
+                    self.log.pop_front();
+                }
+            }
+            None => self.log.clear(),
+        }
+    }
+}
+
+impl<K: Ord, V> OptimisticRollbackMap<K, V> {
+    /// Shares the map to run transactions against it.
+    pub fn new(map: RollbackMap<K, V>) -> Self {
+        OptimisticRollbackMap {
+            shared: Arc::new(Mutex::new(Shared {
+                map,
+                sequence: 0,
+                log: VecDeque::new(),
+                active: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// Begins a new transaction.
+    pub fn begin(&self) -> Transaction<K, V> {
+        let mut shared = self.lock();
+        let start = shared.sequence;
+        *shared.active.entry(start).or_insert(0) += 1;
+        Transaction {
+            shared: Arc::clone(&self.shared),
+            start,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns a copy of the committed value corresponding to the key.
+    ///
+    /// The read is not a part of any transaction.
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.lock().map.get(key).cloned()
+    }
+
+    /// Calls the function with the committed map.
+    pub fn read<R, F: FnOnce(&RollbackMap<K, V>) -> R>(&self, f: F) -> R {
+        f(&self.lock().map)
+    }
+
+    /// Creates a checkpoint of the committed map.
+    /// See [`RollbackMap::checkpoint`].
+    pub fn checkpoint(&self) -> Option<u32> {
+        self.lock().map.checkpoint()
+    }
+
+    /// Rollbacks the committed map to the checkpoint.
+    /// See [`RollbackMap::rollback`].
+    ///
+    /// A successful rollback conflicts with all the active transactions.
+    pub fn rollback(&self, checkpoint: u32) -> bool {
+        let mut shared = self.lock();
+        let rollback = shared.map.rollback(checkpoint);
+        if rollback {
+            shared.commit(None);
+        }
+        rollback
+    }
+
+    /// Deletes all the checkpoints of the committed map except the last one.
+    /// See [`RollbackMap::prune`].
+    pub fn prune(&self) -> Option<u32> {
+        self.lock().map.prune()
+    }
+
+    /// Clears the committed map.
+    /// See [`RollbackMap::clear`].
+    ///
+    /// The clearing conflicts with all the active transactions.
+    pub fn clear(&self) {
+        let mut shared = self.lock();
+        shared.map.clear();
+        shared.commit(None);
+    }
+
+    /// Returns the map if this is the only handle and no transaction is active.
+    pub fn try_into_inner(self) -> Result<RollbackMap<K, V>, Self> {
+        match Arc::try_unwrap(self.shared) {
+            Ok(shared) => Ok(into_inner(shared).map),
+            Err(shared) => Err(OptimisticRollbackMap { shared }),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared<K, V>> {
+        lock(&self.shared)
+    }
+}
+
+/// A transaction over an [`OptimisticRollbackMap`].
+///
+/// Dropping the transaction without committing it discards its writes.
+#[derive(Debug)]
+pub struct Transaction<K: Ord, V> {
+    shared: Arc<Mutex<Shared<K, V>>>,
+    start: u64,
+    reads: BTreeSet<K>,
+    writes: BTreeMap<K, Option<V>>,
+}
+
+impl<K: Ord + Clone, V: Clone> Transaction<K, V> {
+    /// Returns a copy of the value corresponding to the key,
+    /// the transaction's own writes included.
+    ///
+    /// Unless the key was written by the transaction, it is added to the read set.
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        if let Some(value) = self.writes.get(key) {
+            return value.clone();
+        }
+        let value = lock(&self.shared).map.get(key).cloned();
+        self.reads.insert(key.clone());
+        value
+    }
+
+    /// Buffers the insertion of a key-value pair until the commit.
+    ///
+    /// The key is not added to the read set.
+    pub fn insert(&mut self, key: K, value: V) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Buffers the removal of a key until the commit.
+    ///
+    /// The key is not added to the read set.
+    pub fn remove(&mut self, key: &K) {
+        self.writes.insert(key.clone(), None);
+    }
+
+    /// Validates the transaction and applies its writes to the shared map.
+    ///
+    /// Returns [`Conflict`] if any key of the read set was written by another
+    /// transaction committed after this one began. The writes are discarded then.
+    pub fn commit(mut self) -> Result<(), Conflict> {
+        let shared = Arc::clone(&self.shared);
+        let mut shared = lock(&shared);
+        if shared.conflicts(self.start, &self.reads) {
+            return Err(Conflict);
+        }
+        if self.writes.is_empty() {
+            return Ok(());
+        }
+
+        let writes = mem::take(&mut self.writes);
+        let keys = writes.keys().cloned().collect();
+        for (key, value) in writes {
+            match value {
+                Some(value) => {
+                    shared.map.replace(key, value);
+                }
+                None => {
+                    shared.map.remove_key(key);
+                }
+            }
+        }
+        shared.commit(Some(keys));
+        Ok(())
+    }
+}
+
+impl<K: Ord, V> Drop for Transaction<K, V> {
+    fn drop(&mut self) {
+        lock(&self.shared).end(self.start);
+    }
+}
+
+// The shared state stays consistent even if a thread panicked holding the lock:
+// the map is changed only after the commit is validated.
+fn lock<K: Ord, V>(shared: &Mutex<Shared<K, V>>) -> MutexGuard<'_, Shared<K, V>> {
+    match shared.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn into_inner<K: Ord, V>(shared: Mutex<Shared<K, V>>) -> Shared<K, V> {
+    match shared.into_inner() {
+        Ok(shared) => shared,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
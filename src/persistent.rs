@@ -0,0 +1,725 @@
+//! Persistent (structurally shared) rollback map.
+//!
+//! [`PersistentRollbackMap`] keeps its entries in an immutable AVL tree whose
+//! nodes are shared through [`Arc`]. A mutation copies only the path from the
+//! root to the touched node, so every checkpoint is just a saved root pointer
+//! and rolling back swaps that pointer back in.
+use core::borrow::Borrow;
+use core::cmp::Ordering;
+use std::mem;
+use std::sync::Arc;
+use std::vec::Vec;
+
+type Tree<K, V> = Option<Arc<Node<K, V>>>;
+
+#[derive(Clone, Debug)]
+struct Node<K, V> {
+    key: K,
+    value: V,
+    height: usize,
+    left: Tree<K, V>,
+    right: Tree<K, V>,
+}
+
+impl<K, V> Node<K, V> {
+    fn update_height(&mut self) {
+        self.height = 1 + height(&self.left).max(height(&self.right));
+    }
+
+    fn balance(&self) -> isize {
+        height(&self.left) as isize - height(&self.right) as isize
+    }
+}
+
+fn height<K, V>(tree: &Tree<K, V>) -> usize {
+    tree.as_ref().map_or(0, |node| node.height)
+}
+
+fn balance<K, V>(tree: &Tree<K, V>) -> isize {
+    tree.as_ref().map_or(0, |node| node.balance())
+}
+
+fn into_node<K: Clone, V: Clone>(node: Arc<Node<K, V>>) -> Node<K, V> {
+    Arc::try_unwrap(node).unwrap_or_else(|shared| (*shared).clone())
+}
+
+fn rotate_left<K: Clone, V: Clone>(tree: &mut Tree<K, V>) {
+    if let Some(mut root) = tree.take() {
+        let root_node = Arc::make_mut(&mut root);
+        match root_node.right.take() {
+            Some(mut pivot) => {
+                let pivot_node = Arc::make_mut(&mut pivot);
+                root_node.right = pivot_node.left.take();
+                root_node.update_height();
+                pivot_node.left = Some(root);
+                pivot_node.update_height();
+                *tree = Some(pivot);
+            }
+            None => *tree = Some(root),
+        }
+    }
+}
+
+fn rotate_right<K: Clone, V: Clone>(tree: &mut Tree<K, V>) {
+    if let Some(mut root) = tree.take() {
+        let root_node = Arc::make_mut(&mut root);
+        match root_node.left.take() {
+            Some(mut pivot) => {
+                let pivot_node = Arc::make_mut(&mut pivot);
+                root_node.left = pivot_node.right.take();
+                root_node.update_height();
+                pivot_node.right = Some(root);
+                pivot_node.update_height();
+                *tree = Some(pivot);
+            }
+            None => *tree = Some(root),
+        }
+    }
+}
+
+fn rebalance<K: Clone, V: Clone>(tree: &mut Tree<K, V>) {
+    let node = match tree.as_mut() {
+        Some(node) => Arc::make_mut(node),
+        None => return,
+    };
+    node.update_height();
+    let node_balance = node.balance();
+    if node_balance > 1 {
+        if balance(&node.left) < 0 {
+            rotate_left(&mut node.left);
+        }
+        rotate_right(tree);
+    } else if node_balance < -1 {
+        if balance(&node.right) > 0 {
+            rotate_right(&mut node.right);
+        }
+        rotate_left(tree);
+    }
+}
+
+fn get<'a, K, V, Q>(mut tree: &'a Tree<K, V>, key: &Q) -> Option<(&'a K, &'a V)>
+where
+    K: Borrow<Q>,
+    Q: Ord + ?Sized,
+{
+    while let Some(node) = tree {
+        match key.cmp(node.key.borrow()) {
+            Ordering::Less => tree = &node.left,
+            Ordering::Greater => tree = &node.right,
+            Ordering::Equal => return Some((&node.key, &node.value)),
+        }
+    }
+    None
+}
+
+fn insert<K: Ord + Clone, V: Clone>(tree: &mut Tree<K, V>, key: K, value: V) -> Option<V> {
+    let node = match tree.as_mut() {
+        Some(node) => Arc::make_mut(node),
+        None => {
+            *tree = Some(Arc::new(Node {
+                key,
+                value,
+                height: 1,
+                left: None,
+                right: None,
+            }));
+            return None;
+        }
+    };
+    let previous = match key.cmp(&node.key) {
+        Ordering::Less => insert(&mut node.left, key, value),
+        Ordering::Greater => insert(&mut node.right, key, value),
+        Ordering::Equal => return Some(mem::replace(&mut node.value, value)),
+    };
+    if previous.is_none() {
+        rebalance(tree);
+    }
+    previous
+}
+
+fn remove_min<K: Clone, V: Clone>(tree: &mut Tree<K, V>) -> Option<(K, V)> {
+    let node = Arc::make_mut(tree.as_mut()?);
+    if node.left.is_some() {
+        let min = remove_min(&mut node.left);
+        rebalance(tree);
+        return min;
+    }
+    let right = node.right.take();
+    let node = into_node(mem::replace(tree, right)?);
+    Some((node.key, node.value))
+}
+
+// The key must be present, otherwise the path to the missing key is copied for nothing.
+fn remove<K, V, Q>(tree: &mut Tree<K, V>, key: &Q) -> Option<(K, V)>
+where
+    K: Borrow<Q> + Clone,
+    V: Clone,
+    Q: Ord + ?Sized,
+{
+    let node = Arc::make_mut(tree.as_mut()?);
+    let removed = match key.cmp(node.key.borrow()) {
+        Ordering::Less => remove(&mut node.left, key),
+        Ordering::Greater => remove(&mut node.right, key),
+        Ordering::Equal => {
+            if node.left.is_some() && node.right.is_some() {
+                remove_min(&mut node.right).map(|(min_key, min_value)| {
+                    (
+                        mem::replace(&mut node.key, min_key),
+                        mem::replace(&mut node.value, min_value),
+                    )
+                })
+            } else {
+                let child = node.left.take().or_else(|| node.right.take());
+                let node = into_node(mem::replace(tree, child)?);
+                return Some((node.key, node.value));
+            }
+        }
+    };
+    if removed.is_some() {
+        rebalance(tree);
+    }
+    removed
+}
+
+/// An iterator over the entries of a [`PersistentRollbackMap`], sorted by key.
+#[derive(Debug)]
+pub struct Iter<'a, K, V> {
+    stack: Vec<&'a Node<K, V>>,
+    remaining: usize,
+}
+
+impl<'a, K, V> Iter<'a, K, V> {
+    fn new(tree: &'a Tree<K, V>, len: usize) -> Self {
+        let mut iter = Iter {
+            stack: Vec::new(),
+            remaining: len,
+        };
+        iter.push_left(tree);
+        iter
+    }
+
+    fn push_left(&mut self, mut tree: &'a Tree<K, V>) {
+        while let Some(node) = tree {
+            self.stack.push(node);
+            tree = &node.left;
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        self.remaining -= 1;
+        Some((&node.key, &node.value))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+/// An immutable state of a [`PersistentRollbackMap`] saved by a checkpoint.
+///
+/// A snapshot shares its nodes with the map, so it is cheap to make and to clone,
+/// and it stays valid whatever is done to the map afterwards, rollbacks included.
+/// It is `Send` and `Sync` if both `K` and `V` are.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::PersistentRollbackMap;
+///
+/// let mut map = PersistentRollbackMap::new();
+/// map.insert(1, "a");
+/// let checkpoint = map.checkpoint().unwrap();
+/// let snapshot = map.snapshot(checkpoint).unwrap();
+/// map.insert(1, "b");
+/// map.prune();
+/// map.clear();
+/// assert_eq!(snapshot.checkpoint(), checkpoint);
+/// assert_eq!(snapshot.get(&1), Some(&"a"));
+/// assert_eq!(map.get(&1), None);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Snapshot<K, V> {
+    checkpoint: u32,
+    root: Tree<K, V>,
+    len: usize,
+}
+
+impl<K, V> Snapshot<K, V> {
+    /// Returns the checkpoint the snapshot was saved by.
+    pub fn checkpoint(&self) -> u32 {
+        self.checkpoint
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        get(&self.root, key).map(|(_, v)| v)
+    }
+
+    /// Returns `true` if the snapshot contains a value for the specified key.
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        get(&self.root, key).is_some()
+    }
+
+    /// Returns the number of elements in the snapshot.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the snapshot contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets an iterator over the entries of the snapshot, sorted by key.
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.root, self.len)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct SavedRoot<K, V> {
+    checkpoint: u32,
+    root: Tree<K, V>,
+    len: usize,
+}
+
+/// A map with rollback support built on a persistent tree.
+///
+/// It offers the same operations as [`RollbackMap`](crate::RollbackMap), but
+/// the whole map state is shared between checkpoints instead of being split
+/// into difference layers:
+/// - `checkpoint` stores the current root pointer in O(1);
+/// - `rollback` swaps a saved root pointer back in;
+/// - `clone` of the whole map, checkpoints included, is O(checkpoints count).
+///
+/// Mutations copy the O(log n) nodes on the path to the touched key,
+/// which is why they need `K: Clone` and `V: Clone`.
+#[derive(Clone, Debug)]
+pub struct PersistentRollbackMap<K, V> {
+    root: Tree<K, V>,
+    len: usize,
+    checkpoint: u32,
+    saved: Vec<SavedRoot<K, V>>,
+}
+
+impl<K, V> Default for PersistentRollbackMap<K, V> {
+    /// Creates an empty `PersistentRollbackMap`.
+    fn default() -> Self {
+        PersistentRollbackMap::new()
+    }
+}
+
+// Implementation of basic map functions
+impl<K, V> PersistentRollbackMap<K, V> {
+    /// Makes a new, empty `PersistentRollbackMap`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    ///
+    /// // entries can now be inserted into the empty map
+    /// map.insert(1, "a");
+    /// ```
+    pub fn new() -> Self {
+        PersistentRollbackMap {
+            root: None,
+            len: 0,
+            checkpoint: 0,
+            saved: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the value corresponding to the key.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.get(&1), Some(&"a"));
+    /// assert_eq!(map.get(&2), None);
+    /// ```
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        get(&self.root, key).map(|(_, v)| v)
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// assert!(map.contains_key(&1));
+    /// assert!(!map.contains_key(&2));
+    /// ```
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        get(&self.root, key).is_some()
+    }
+
+    /// Returns the number of elements in the map.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// assert_eq!(map.len(), 0);
+    /// map.insert(1, "a");
+    /// assert_eq!(map.len(), 1);
+    /// ```
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the map contains no elements.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// assert!(map.is_empty());
+    /// map.insert(1, "a");
+    /// assert!(!map.is_empty());
+    /// ```
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets an iterator over the entries of the map, sorted by key.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(2, "b");
+    /// map.insert(1, "a");
+    /// let entries: Vec<_> = map.iter().collect();
+    /// assert_eq!(entries, vec![(&1, &"a"), (&2, &"b")]);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter::new(&self.root, self.len)
+    }
+
+    /// Clears data in the map.
+    /// Data can be restored if was saved by checkpoint call.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.clear();
+    /// assert!(map.is_empty());
+    /// assert!(map.rollback(checkpoint));
+    /// assert_eq!(map.get(&1), Some(&"a"));
+    /// ```
+    pub fn clear(&mut self) {
+        self.root = None;
+        self.len = 0;
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> PersistentRollbackMap<K, V> {
+    /// Inserts a key-value pair into the map.
+    ///
+    /// If the map did not have this key present, `None` is returned.
+    ///
+    /// If the map did have this key present, the value is updated, and the old
+    /// value is returned. The key is not updated.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(37, "b");
+    /// assert_eq!(map.insert(37, "c"), Some("b"));
+    /// assert_eq!(map.get(&37), Some(&"c"));
+    /// ```
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let previous = insert(&mut self.root, key, value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    /// Removes a key from the map, returning the value at the key if the key
+    /// was previously in the map.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// assert_eq!(map.remove(&1), Some("a"));
+    /// assert_eq!(map.remove(&1), None);
+    /// ```
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        if !self.contains_key(key) {
+            return None;
+        }
+        let removed = remove(&mut self.root, key);
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed.map(|(_, v)| v)
+    }
+}
+
+// Implementation of versioning functions
+impl<K, V> PersistentRollbackMap<K, V> {
+    /// Saves the current root and returns the checkpoint that can be used to rollback to.
+    /// See [`RollbackMap::checkpoint`](crate::RollbackMap::checkpoint).
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "xa");
+    /// map.remove(&2);
+    /// assert!(map.rollback(checkpoint));
+    /// assert_eq!(map.get(&1), Some(&"a"));
+    /// assert_eq!(map.get(&2), Some(&"b"));
+    /// ```
+    pub fn checkpoint(&mut self) -> Option<u32> {
+        let checkpoint = self.checkpoint;
+        self.saved.push(SavedRoot {
+            checkpoint,
+            root: self.root.clone(),
+            len: self.len,
+        });
+        self.checkpoint += 1;
+        Some(checkpoint)
+    }
+
+    /// Returns last created checkpoint if any.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map: PersistentRollbackMap<u32, &str> = PersistentRollbackMap::new();
+    /// assert_eq!(map.get_last_checkpoint(), None);
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// assert_eq!(map.get_last_checkpoint(), Some(checkpoint));
+    /// ```
+    pub fn get_last_checkpoint(&self) -> Option<u32> {
+        self.saved.last().map(|saved| saved.checkpoint)
+    }
+
+    /// Returns checkpoint before the last one saved.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map: PersistentRollbackMap<u32, &str> = PersistentRollbackMap::new();
+    /// let first_checkpoint = map.checkpoint().unwrap();
+    /// assert_eq!(map.get_prev_checkpoint(), None);
+    /// map.checkpoint();
+    /// assert_eq!(map.get_prev_checkpoint(), Some(first_checkpoint));
+    /// ```
+    pub fn get_prev_checkpoint(&self) -> Option<u32> {
+        if self.saved.len() < 2 {
+            return None;
+        }
+        Some(self.saved[self.saved.len() - 2].checkpoint)
+    }
+
+    /// Returns checkpoint count.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map: PersistentRollbackMap<u32, &str> = PersistentRollbackMap::new();
+    /// map.checkpoint();
+    /// map.checkpoint();
+    /// assert_eq!(map.get_checkpoints_count(), 2);
+    /// ```
+    pub fn get_checkpoints_count(&self) -> usize {
+        self.saved.len()
+    }
+
+    /// Returns the map state saved by the checkpoint if it was not deleted.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.insert(2, "b");
+    /// let snapshot = map.snapshot(checkpoint).unwrap();
+    /// assert_eq!(snapshot.len(), 1);
+    /// assert!(map.snapshot(checkpoint + 1).is_none());
+    /// ```
+    pub fn snapshot(&self, checkpoint: u32) -> Option<Snapshot<K, V>> {
+        let saved = self
+            .saved
+            .iter()
+            .rfind(|saved| saved.checkpoint == checkpoint)?;
+        Some(Snapshot {
+            checkpoint,
+            root: saved.root.clone(),
+            len: saved.len,
+        })
+    }
+
+    /// Rollbacks to saved checkpoint.
+    /// Rollback is only possible in backward direction.
+    /// If the rollback is done successfully, true is returned, false otherwise.
+    /// Successful rollback deletes all the changes and checkpoints that were
+    /// done after the provided checkpoint.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// let first_checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "xa");
+    /// let second_checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "xb");
+    /// assert!(map.rollback(first_checkpoint));
+    /// assert_eq!(map.get(&1), Some(&"a"));
+    /// assert!(!map.rollback(second_checkpoint));
+    /// ```
+    pub fn rollback(&mut self, checkpoint: u32) -> bool {
+        let index = match self
+            .saved
+            .iter()
+            .rposition(|saved| saved.checkpoint == checkpoint)
+        {
+            Some(index) => index,
+            None => return false,
+        };
+        self.saved.truncate(index + 1);
+        let saved = &self.saved[index];
+        self.root = saved.root.clone();
+        self.len = saved.len;
+        self.checkpoint = checkpoint + 1;
+        true
+    }
+
+    /// Deletes all the checkpoints except the last one.
+    /// Returns the last saved checkpoint if any.
+    ///
+    /// Nodes that are no longer referenced by any remaining checkpoint
+    /// (or by a clone of the map) are freed.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::PersistentRollbackMap;
+    ///
+    /// let mut map = PersistentRollbackMap::new();
+    /// map.insert(1, "a");
+    /// let first_checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "xa");
+    /// let second_checkpoint = map.checkpoint().unwrap();
+    /// assert_eq!(map.prune(), Some(second_checkpoint));
+    /// assert!(!map.rollback(first_checkpoint));
+    /// assert!(map.rollback(second_checkpoint));
+    /// assert_eq!(map.get(&1), Some(&"xa"));
+    /// ```
+    pub fn prune(&mut self) -> Option<u32> {
+        let excess = self.saved.len().saturating_sub(1);
+        self.saved.drain(..excess);
+        self.get_last_checkpoint()
+    }
+}
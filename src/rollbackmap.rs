@@ -1,11 +1,31 @@
 use core::borrow::Borrow;
+use core::hash::{Hash, Hasher};
+use core::iter::FromIterator;
+use core::ops::{Bound, Deref, Index};
+use std::collections::btree_map;
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::mem;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::vec::Vec;
 
-#[derive(Debug)]
+use crate::budget::Budget;
+use crate::events::{Event, Observers};
+use crate::validate::debug_check;
+
+#[cfg(feature = "serde")]
+use serde::ser::SerializeStruct;
+#[cfg(feature = "serde")]
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(
+    feature = "serde",
+    serde(bound(deserialize = "K: Ord + Deserialize<'de>, V: Deserialize<'de>"))
+)]
 pub struct VersionState<K, V> {
-    /// Keys that are requested to be removed, but are present only in the previous versions
+    /// Keys removed in this version that are visible in the previous versions
     pub removed_keys: BTreeSet<K>,
 
     /// Current version added nodes
@@ -17,8 +37,12 @@ pub struct VersionState<K, V> {
     /// Checkpoint
     pub checkpoint: u32,
 
-    /// Count of values
+    /// Count of the values visible in this version, including the previous versions
     pub values_count: usize,
+
+    /// Is set when the checkpoint is pruned, but its layer is kept for a pin
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub retired: bool,
 }
 
 impl<K, V> VersionState<K, V>
@@ -30,16 +54,11 @@ where
             removed_keys: BTreeSet::new(),
             data: BTreeMap::new(),
             detached: false,
-            checkpoint: checkpoint,
-            values_count: values_count,
+            checkpoint,
+            values_count,
+            retired: false,
         }
     }
-    pub fn reset(&mut self, values_count: usize) {
-        self.removed_keys.clear();
-        self.data.clear();
-        self.detached = false;
-        self.values_count = values_count;
-    }
 }
 
 /// A map that provides rolling back functionality.
@@ -48,17 +67,176 @@ where
 /// - create checkpoint;
 /// - rollback (only in backward direction) to some specific checkpoint;
 /// - remove all created checkpoints except the last one;
+///
+/// Cloning the map clones all of its checkpoints as well.
+/// Equality and hashing only consider the visible key-value pairs,
+/// use [`RollbackMap::history_eq`] to compare the checkpoints too.
+///
+/// With the `serde` feature enabled the map is serialized with all of its checkpoints.
+/// Use the [`flat`](crate::flat) module to serialize only the visible key-value pairs.
+///
+/// Checkpoints can be pinned by [`RollbackMap::pin`] to read their state
+/// while the map keeps changing. Pins are not cloned with the map.
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct RollbackMap<K, V>
 where
     K: Ord,
 {
-    versions: Vec<VersionState<K, V>>,
+    pub(crate) versions: Vec<VersionState<K, V>>,
+    pub(crate) pins: Pins,
+    pub(crate) observers: Observers<K>,
+    pub(crate) budget: Option<Budget<K, V>>,
+    // Is set if the oldest version is based on versions kept outside of the map,
+    // so the validation cannot merge the view below it.
+    pub(crate) partial: bool,
+}
+
+impl<K: Ord, V> Default for RollbackMap<K, V> {
+    /// Creates an empty `RollbackMap`.
+    fn default() -> Self {
+        RollbackMap::new()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<K: Ord + Serialize, V: Serialize> Serialize for RollbackMap<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut state = serializer.serialize_struct("RollbackMap", 1)?;
+        state.serialize_field("versions", &self.versions)?;
+        state.end()
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, K, V> Deserialize<'de> for RollbackMap<K, V>
+where
+    K: Ord + Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(
+            rename = "RollbackMap",
+            bound = "K: Ord + Deserialize<'de>, V: Deserialize<'de>"
+        )]
+        struct History<K, V> {
+            versions: Vec<VersionState<K, V>>,
+        }
+
+        let versions = History::deserialize(deserializer)?.versions;
+        if versions.is_empty() {
+            return Err(de::Error::custom("RollbackMap has no versions"));
+        }
+        if versions
+            .windows(2)
+            .any(|pair| pair[0].checkpoint >= pair[1].checkpoint)
+        {
+            return Err(de::Error::custom(
+                "RollbackMap checkpoints are not strictly increasing",
+            ));
+        }
+        if !retired_versions_valid(&versions) {
+            return Err(de::Error::custom(
+                "RollbackMap retired versions are not the oldest ones",
+            ));
+        }
+        let map = RollbackMap::from_versions(versions);
+        if let Err(inconsistency) = map.validate() {
+            return Err(de::Error::custom(format_args!(
+                "RollbackMap is inconsistent: {}",
+                inconsistency
+            )));
+        }
+        Ok(map)
+    }
+}
+
+impl<K: Ord, V: PartialEq> PartialEq for RollbackMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.visible() == other.visible()
+    }
+}
+
+impl<K: Ord, V: Eq> Eq for RollbackMap<K, V> {}
+
+impl<K: Ord + Hash, V: Hash> Hash for RollbackMap<K, V> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.visible().hash(state);
+    }
+}
+
+/// A key reverted by [`RollbackMap::rollback_with_changes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Change<K, V> {
+    /// The reverted key.
+    pub key: K,
+    /// Value before the rollback, `None` if the key was not present.
+    pub before: Option<V>,
+    /// Value after the rollback, `None` if the key is not present.
+    pub after: Option<V>,
+}
+
+/// A value that was replaced or removed from a `RollbackMap`.
+///
+/// A value that lived in the current version is moved out of the map.
+/// A value from an older version has to be kept for a possible rollback,
+/// so only a reference to it is returned.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Previous<'a, V> {
+    /// The value was moved out of the current version.
+    Owned(V),
+    /// The value is still kept by a checkpoint.
+    Shadowed(&'a V),
+}
+
+impl<'a, V> Previous<'a, V> {
+    /// Returns the owned value, cloning it if it is kept by a checkpoint.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::{Previous, RollbackMap};
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a".to_owned());
+    /// map.checkpoint();
+    /// let previous = map.replace(1, "b".to_owned()).unwrap();
+    /// assert_eq!(previous, Previous::Shadowed(&"a".to_owned()));
+    /// assert_eq!(previous.into_owned(), "a");
+    /// ```
+    pub fn into_owned(self) -> V
+    where
+        V: Clone,
+    {
+        match self {
+            Previous::Owned(value) => value,
+            Previous::Shadowed(value) => value.clone(),
+        }
+    }
+}
+
+impl<'a, V> Deref for Previous<'a, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match self {
+            Previous::Owned(value) => value,
+            Previous::Shadowed(value) => value,
+        }
+    }
+}
+
+impl<'a, V> AsRef<V> for Previous<'a, V> {
+    fn as_ref(&self) -> &V {
+        self
+    }
 }
 
 // Implementation of basic map functions
-impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
+impl<K: Ord, V> RollbackMap<K, V> {
     /// Makes a new, empty `RollbackMap`.
     ///
     ///
@@ -75,8 +253,16 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// map.insert(1, "a");
     /// ```
     pub fn new() -> Self {
+        RollbackMap::from_versions(vec![VersionState::new(0, 0)])
+    }
+
+    pub(crate) fn from_versions(versions: Vec<VersionState<K, V>>) -> Self {
         RollbackMap {
-            versions: vec![VersionState::new(0, 0)],
+            versions,
+            pins: Pins::default(),
+            observers: Observers::default(),
+            budget: None,
+            partial: false,
         }
     }
 
@@ -87,6 +273,9 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// If the map did have this key present, the value is updated, and the old
     /// value is returned. The key is not updated.
     ///
+    /// The old value is cloned if it is kept by a checkpoint,
+    /// use [`RollbackMap::replace`] to avoid that.
+    ///
     /// # Examples
     ///
     /// Basic usage:
@@ -101,30 +290,99 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// ```
     pub fn insert(&mut self, key: K, value: V) -> Option<V>
     where
-        K: Ord,
+        V: Clone,
     {
-        let mut pv: Option<V> = None;
-        if let Some(last) = self.versions.last() {
-            if !last.removed_keys.contains(&key) {
-                if let Some(existing) = self.deep_get_key_value(&key) {
-                    pv = Some(existing.1.clone())
-                }
+        self.replace(key, value).map(Previous::into_owned)
+    }
+
+    /// Inserts a key-value pair into the map.
+    /// Returns `true` if the map did have this key present.
+    ///
+    /// Unlike [`RollbackMap::insert`], the old value is never cloned, nor are the values
+    /// required to implement `Clone`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// assert!(!map.set(1, "a"));
+    /// map.checkpoint();
+    /// assert!(map.set(1, "b"));
+    /// assert_eq!(map.get(&1), Some(&"b"));
+    /// assert_eq!(map.len(), 1);
+    /// ```
+    pub fn set(&mut self, key: K, value: V) -> bool {
+        self.replace(key, value).is_some()
+    }
+
+    /// Inserts a key-value pair into the map without cloning the old value.
+    ///
+    /// If the map did not have this key present, `None` is returned.
+    ///
+    /// If the old value lived in the current version, it is moved out of the map.
+    /// If it is kept by a checkpoint, a reference to it is returned instead.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::{Previous, RollbackMap};
+    ///
+    /// let mut map = RollbackMap::new();
+    /// assert_eq!(map.replace(1, "a"), None);
+    /// map.checkpoint();
+    /// assert_eq!(map.replace(1, "b"), Some(Previous::Shadowed(&"a")));
+    /// assert_eq!(map.replace(1, "c"), Some(Previous::Owned("b")));
+    /// ```
+    pub fn replace(&mut self, key: K, value: V) -> Option<Previous<'_, V>> {
+        self.replace_with(key, value, get_key_value)
+    }
+
+    // Same as replace, the value of the key in the older versions is found by the function.
+    pub(crate) fn replace_with<F>(&mut self, key: K, value: V, find: F) -> Option<Previous<'_, V>>
+    where
+        F: for<'a> FnOnce(&'a [VersionState<K, V>], &K) -> Option<(&'a K, &'a V)>,
+    {
+        let (last, older) = split_last_mut(&mut self.versions);
+        let entry = match last.data.entry(key) {
+            btree_map::Entry::Occupied(mut entry) => {
+                let previous = entry.insert(value);
+                self.observers.notify(&Event::Updated(entry.key()));
+                debug_check(older, last, self.partial);
+                return Some(Previous::Owned(previous));
             }
-        }
+            btree_map::Entry::Vacant(entry) => entry,
+        };
 
-        if let Some(last) = self.versions.last_mut() {
-            last.removed_keys.remove(&key);
-            last.data.insert(key, value);
-            if pv.is_none() {
+        let shadowed = if last.removed_keys.remove(entry.key()) || last.detached {
+            None
+        } else {
+            find(older, entry.key()).map(|(_, v)| v)
+        };
+
+        let entry = entry.insert_entry(value);
+        self.observers.notify(&match shadowed {
+            Some(_) => Event::Updated(entry.key()),
+            None => {
                 last.values_count += 1;
+                Event::Inserted(entry.key())
             }
-        }
-
-        return pv;
+        });
+        debug_check(older, last, self.partial);
+        shadowed.map(Previous::Shadowed)
     }
+
     /// Removes a key from the map, returning the value at the key if the key
     /// was previously in the map.
     ///
+    /// The value is cloned if it is kept by a checkpoint,
+    /// use [`RollbackMap::take`] to avoid that.
+    ///
     /// # Examples
     ///
     /// Basic usage:
@@ -137,35 +395,134 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// assert_eq!(map.remove(&1), Some("a"));
     /// assert_eq!(map.remove(&1), None);
     /// ```
-    pub fn remove<Q: ?Sized>(&mut self, key: &Q) -> Option<V>
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord,
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
     {
-        if let Some(last) = self.versions.last_mut() {
-            if last.data.contains_key(key) {
-                last.values_count -= 1;
-                return last.data.remove(key);
-            }
-        }
+        self.take(key).map(Previous::into_owned)
+    }
 
-        let mut found: Option<(K, V)> = None;
-        {
-            let result = self.deep_get_key_value(&*key);
-            if result.is_some() {
-                found = Some((result.unwrap().0.clone(), result.unwrap().1.clone()));
+    /// Removes a key from the map.
+    /// Returns `true` if the map did have this key present.
+    ///
+    /// Unlike [`RollbackMap::remove`], the value is never cloned, nor are the values
+    /// required to implement `Clone`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.checkpoint();
+    /// assert!(map.delete(&1));
+    /// assert!(!map.delete(&1));
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn delete<Q>(&mut self, key: &Q) -> bool
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+    {
+        self.take(key).is_some()
+    }
+
+    /// Removes a key from the map without cloning its value.
+    ///
+    /// If the value lived in the current version, it is moved out of the map.
+    /// If it is kept by a checkpoint, a reference to it is returned instead.
+    /// Only the key is cloned to record the removal.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::{Previous, RollbackMap};
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.checkpoint();
+    /// map.insert(2, "c");
+    /// assert_eq!(map.take(&1), Some(Previous::Shadowed(&"a")));
+    /// assert_eq!(map.take(&2), Some(Previous::Owned("c")));
+    /// assert_eq!(map.take(&1), None);
+    /// ```
+    pub fn take<Q>(&mut self, key: &Q) -> Option<Previous<'_, V>>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+    {
+        self.take_with(key, get_key_value)
+    }
+
+    // Same as take, the value of the key in the older versions is found by the function.
+    pub(crate) fn take_with<Q, F>(&mut self, key: &Q, find: F) -> Option<Previous<'_, V>>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        F: for<'a> FnOnce(&'a [VersionState<K, V>], &Q) -> Option<(&'a K, &'a V)>,
+    {
+        let (last, older) = split_last_mut(&mut self.versions);
+        if let Some((removed_key, value)) = last.data.remove_entry(key) {
+            last.values_count -= 1;
+            // The value kept by a checkpoint must stay hidden
+            match find(older, key) {
+                Some((found_key, _)) if !last.detached => {
+                    last.removed_keys.insert(removed_key);
+                    self.observers.notify(&Event::Removed(found_key));
+                }
+                _ => self.observers.notify(&Event::Removed(&removed_key)),
             }
+            debug_check(older, last, self.partial);
+            return Some(Previous::Owned(value));
         }
+        if last.removed_keys.contains(key) || last.detached {
+            return None;
+        }
+
+        let (found_key, found_value) = find(older, key)?;
+        last.values_count -= 1;
+        last.removed_keys.insert(found_key.clone());
+        self.observers.notify(&Event::Removed(found_key));
+        debug_check(older, last, self.partial);
+        Some(Previous::Shadowed(found_value))
+    }
 
-        if found.is_some() {
-            if let Some(last) = self.versions.last_mut() {
-                last.values_count -= 1;
-                last.removed_keys.insert(found.as_ref().unwrap().0.clone());
+    // Same as take, but the owned key is used to record the removal instead of a clone.
+    // Returns true if the key was present.
+    pub(crate) fn remove_key(&mut self, key: K) -> bool {
+        let (last, older) = split_last_mut(&mut self.versions);
+        if last.data.remove(&key).is_some() {
+            last.values_count -= 1;
+            match get_key_value(older, &key) {
+                Some((found_key, _)) if !last.detached => {
+                    last.removed_keys.insert(key);
+                    self.observers.notify(&Event::Removed(found_key));
+                }
+                _ => self.observers.notify(&Event::Removed(&key)),
             }
-            return Some(found.as_ref().unwrap().1.clone());
+            debug_check(older, last, self.partial);
+            return true;
         }
-
-        return None;
+        if last.removed_keys.contains(&key) || last.detached {
+            return false;
+        }
+        let found_key = match get_key_value(older, &key) {
+            Some((found_key, _)) => found_key,
+            None => return false,
+        };
+        last.values_count -= 1;
+        last.removed_keys.insert(key);
+        self.observers.notify(&Event::Removed(found_key));
+        debug_check(older, last, self.partial);
+        true
     }
 
     /// Returns `true` if the map contains a value for the specified key.
@@ -185,13 +542,12 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// assert_eq!(map.contains_key(&1), true);
     /// assert_eq!(map.contains_key(&2), false);
     /// ```
-    pub fn contains_key<Q: ?Sized>(&self, key: &Q) -> bool
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord,
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
     {
-        let key_value = self.deep_get_key_value(key);
-        return key_value.is_some();
+        self.deep_get_key_value(key).is_some()
     }
 
     /// Returns a reference to the value corresponding to the key.
@@ -208,38 +564,64 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// assert_eq!(map.get(&1), Some(&"a"));
     /// assert_eq!(map.get(&2), None);
     /// ```
-    pub fn get<Q: ?Sized>(&self, key: &Q) -> Option<&V>
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord,
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
     {
-        let key_value = self.deep_get_key_value(key);
-        if !key_value.is_some() {
-            return None;
-        }
-
-        return Some(key_value.unwrap().1);
+        self.deep_get_key_value(key).map(|(_, v)| v)
     }
 
-    fn deep_get_key_value<Q: ?Sized>(&self, key: &Q) -> Option<(&K, &V)>
+    /// Returns a mutable reference to the value corresponding to the key.
+    ///
+    /// A value kept by a checkpoint is cloned into the current version first,
+    /// so the checkpoint still has the original value to rollback to.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a".to_owned());
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.get_mut(&1).unwrap().push('b');
+    /// assert_eq!(map[&1], "ab");
+    /// map.rollback(checkpoint);
+    /// assert_eq!(map[&1], "a");
+    /// ```
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
     where
-        K: Borrow<Q> + Ord,
-        Q: Ord,
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
     {
-        for version in self.versions.iter().rev() {
-            let key_value = version.data.get_key_value(key);
-            if key_value.is_some() {
-                return key_value;
-            }
-            if version.removed_keys.contains(key) {
-                return None;
-            }
-            if version.detached {
-                break;
+        let (last, older) = split_last_mut(&mut self.versions);
+        match last.data.get_key_value(key) {
+            Some((found_key, _)) => self.observers.notify(&Event::Updated(found_key)),
+            None => {
+                if last.removed_keys.contains(key) || last.detached {
+                    return None;
+                }
+                let (found_key, found_value) = get_key_value(older, key)?;
+                last.data.insert(found_key.clone(), found_value.clone());
+                self.observers.notify(&Event::Updated(found_key));
             }
         }
-        return None;
+        debug_check(older, last, self.partial);
+        last.data.get_mut(key)
     }
+
+    pub(crate) fn deep_get_key_value<Q>(&self, key: &Q) -> Option<(&K, &V)>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        get_key_value(&self.versions, key)
+    }
+
     /// Clears data in the RollbackMap instance.
     /// Data can be restored if was saved by checkpoint call.
     ///
@@ -263,6 +645,8 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
             last.detached = true;
             last.values_count = 0;
         }
+        self.observers.notify(&Event::Cleared);
+        self.debug_check();
     }
 
     /// Returns the number of elements in the map.
@@ -283,7 +667,7 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
         if let Some(last) = self.versions.last() {
             return last.values_count;
         }
-        return 0;
+        0
     }
 
     /// Returns `true` if the map contains no elements.
@@ -301,7 +685,104 @@ impl<K: Ord + Clone, V: Clone> RollbackMap<K, V> {
     /// assert!(!a.is_empty());
     /// ```
     pub fn is_empty(&self) -> bool {
-        return self.len() == 0;
+        self.len() == 0
+    }
+
+    /// Checks the invariants of the map layers with [`RollbackMap::validate`], among them
+    /// that the tracked length of every layer matches the count of its visible values.
+    ///
+    /// The length is updated by every insertion and removal, so that [`RollbackMap::len`]
+    /// costs nothing. The check merges all the layers, so it is only run in debug builds,
+    /// in release builds it does nothing.
+    ///
+    /// # Panics
+    ///
+    /// Panics with the first inconsistency found.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.checkpoint();
+    /// map.insert(1, "b");
+    /// map.remove(&1);
+    ///
+    /// map.debug_assert_consistency();
+    /// assert_eq!(map.len(), 0);
+    /// ```
+    pub fn debug_assert_consistency(&self) {
+        if cfg!(debug_assertions) {
+            self.assert_valid();
+        }
+    }
+
+    /// Gets an iterator over the visible entries of the map, sorted by key.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(3, "c");
+    /// map.insert(1, "a");
+    /// map.checkpoint();
+    /// map.insert(2, "b");
+    /// map.remove(&3);
+    /// let entries: Vec<_> = map.iter().collect();
+    /// assert_eq!(entries, vec![(&1, &"a"), (&2, &"b")]);
+    /// ```
+    pub fn iter(&self) -> Iter<'_, K, V> {
+        Iter {
+            inner: self.visible().into_iter(),
+        }
+    }
+}
+
+/// A `RollbackMap` that shares values between checkpoints by reference.
+///
+/// Replacing or removing a value returns a cheap clone of its `Arc`,
+/// and [`RollbackMap::make_mut`] copies a value only when it is modified
+/// while still being referenced by a checkpoint.
+pub type SharedRollbackMap<K, V> = RollbackMap<K, Arc<V>>;
+
+// Implementation of shared values functions
+impl<K: Ord + Clone, V: Clone> RollbackMap<K, Arc<V>> {
+    /// Returns a mutable reference to the shared value corresponding to the key.
+    ///
+    /// Works like [`Arc::make_mut`]: the value is cloned only if it is still
+    /// referenced elsewhere, e.g. by a checkpoint or by a returned `Arc`.
+    /// Further modifications of the same value in the current version are done in place.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::SharedRollbackMap;
+    /// use std::sync::Arc;
+    ///
+    /// let mut map = SharedRollbackMap::new();
+    /// map.insert(1, Arc::new(vec![0u8; 1024]));
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.make_mut(&1).unwrap()[0] = 1;
+    /// assert_eq!(map[&1][0], 1);
+    /// map.rollback(checkpoint);
+    /// assert_eq!(map[&1][0], 0);
+    /// ```
+    pub fn make_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.get_mut(key).map(Arc::make_mut)
     }
 }
 
@@ -334,9 +815,12 @@ impl<K: Ord, V> RollbackMap<K, V> {
             let values_count = last.values_count;
             self.versions
                 .push(VersionState::new(version + 1, values_count));
+            self.observers.notify(&Event::CheckpointCreated(version));
+            self.enforce_budget();
+            self.debug_check();
             return Some(version);
         }
-        return None;
+        None
     }
 
     /// Returns last created checkpoint if any.
@@ -366,7 +850,7 @@ impl<K: Ord, V> RollbackMap<K, V> {
 
         let prev_index = self.versions.len() - 2;
 
-        return Some(self.versions[prev_index].checkpoint);
+        Some(self.versions[prev_index].checkpoint)
     }
 
     /// Returns checkpoint before the last one saved.
@@ -403,7 +887,11 @@ impl<K: Ord, V> RollbackMap<K, V> {
             return None;
         }
         let prev_index = self.versions.len() - 3;
-        return Some(self.versions[prev_index].checkpoint);
+        let prev = &self.versions[prev_index];
+        if prev.retired {
+            return None;
+        }
+        Some(prev.checkpoint)
     }
 
     // Returns checkpoint count.
@@ -429,7 +917,7 @@ impl<K: Ord, V> RollbackMap<K, V> {
             return 0;
         }
 
-        return self.versions.len() - 1;
+        self.versions.len() - 1 - self.retired_count()
     }
 
     /// Rollbacks to saved checkpoint.
@@ -437,6 +925,8 @@ impl<K: Ord, V> RollbackMap<K, V> {
     /// If the rollback is done successfully, true is returned, false otherwise.
     /// Successful rollback deletes all the changes that were done the provided checkpoint.
     ///
+    /// The rollback fails while any of the checkpoints it would delete is pinned.
+    ///
     /// # Examples
     ///
     /// Basic usage:
@@ -465,40 +955,91 @@ impl<K: Ord, V> RollbackMap<K, V> {
     /// assert_eq!(false, map.rollback(second_checkpoint.unwrap()));
     /// ```
     pub fn rollback(&mut self, checkpoint: u32) -> bool {
-        let mut found = false;
-        let mut values_count = 0;
-        for version in self.versions.iter().rev() {
-            if version.checkpoint == checkpoint {
-                found = true;
-                values_count = version.values_count;
-                break;
-            }
-        }
+        let index = match self.rollback_index(checkpoint) {
+            Some(index) => index,
+            None => return false,
+        };
 
-        if !found {
-            return false;
+        let values_count = self.versions[index].values_count;
+        let current = self.versions[index + 1].checkpoint;
+        let discarded = self.versions.split_off(index + 1);
+        self.versions.push(VersionState::new(current, values_count));
+        if let Some(budget) = self.budget.as_mut() {
+            budget.truncate(index + 1);
         }
+        self.debug_check();
 
-        let mut rollback = false;
-        while self.versions.len() >= 2 {
-            if let Some(last_checkpoint) = self.get_last_checkpoint() {
-                if last_checkpoint == checkpoint {
-                    if let Some(last) = self.versions.last_mut() {
-                        last.reset(values_count);
-                        rollback = true;
-                        break;
-                    }
-                }
-            }
-            self.versions.pop();
+        if !self.observers.is_empty() {
+            let reverted_keys = reverted_keys(&self.versions[..=index], &discarded)
+                .into_iter()
+                .collect();
+            self.observers.notify(&Event::RolledBack {
+                to: checkpoint,
+                reverted_keys,
+            });
         }
+        true
+    }
 
-        return rollback;
+    /// Rollbacks to saved checkpoint like [`RollbackMap::rollback`],
+    /// but returns the changes it reverted instead of `true`.
+    ///
+    /// There is a change for every key touched after the checkpoint, sorted by key,
+    /// with its value before and after the rollback. A key touched by a clear
+    /// is any key of the checkpoint. Values that were changed back and forth
+    /// may be equal before and after the rollback.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    /// ```
+    /// use crate::rollbackmap::{Change, RollbackMap};
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "x");
+    /// map.remove(&2);
+    /// map.insert(3, "c");
+    ///
+    /// let changes = map.rollback_with_changes(checkpoint).unwrap();
+    /// assert_eq!(
+    ///     changes,
+    ///     vec![
+    ///         Change { key: 1, before: Some("x"), after: Some("a") },
+    ///         Change { key: 2, before: None, after: Some("b") },
+    ///         Change { key: 3, before: Some("c"), after: None },
+    ///     ]
+    /// );
+    /// assert_eq!(map.rollback_with_changes(checkpoint + 1), None);
+    /// ```
+    pub fn rollback_with_changes(&mut self, checkpoint: u32) -> Option<Vec<Change<K, V>>>
+    where
+        K: Clone,
+        V: Clone,
+    {
+        let index = self.rollback_index(checkpoint)?;
+        let (kept, discarded) = self.versions.split_at(index + 1);
+        let changes = reverted_keys(kept, discarded)
+            .into_iter()
+            .map(|key| Change {
+                key: key.clone(),
+                before: get_key_value(&self.versions, key).map(|(_, v)| v.clone()),
+                after: get_key_value(&self.versions[..=index], key).map(|(_, v)| v.clone()),
+            })
+            .collect();
+        self.rollback(checkpoint);
+        Some(changes)
     }
 
     /// Deletes all the checkpoints except the last one.
     /// Returns the last saved checkpoint if any.
     ///
+    /// The changes of the deleted checkpoints are folded into the last one.
+    /// The layers of the pinned checkpoints are kept until they are unpinned
+    /// and the map is pruned again, but the checkpoints are deleted right away.
+    ///
     /// # Examples
     ///
     /// Basic usage:
@@ -529,9 +1070,526 @@ impl<K: Ord, V> RollbackMap<K, V> {
     /// assert_eq!(map.get(&2), None);
     /// ```
     pub fn prune(&mut self) -> Option<u32> {
-        while self.versions.len() > 2 {
-            self.versions.remove(0);
+        if self.versions.len() > 2 {
+            let pinned = self.pins.lock();
+            let current = self.versions.pop();
+            let saved = mem::take(&mut self.versions);
+            let last_index = saved.len() - 1;
+
+            let mut run: Option<VersionState<K, V>> = None;
+            for (index, version) in saved.into_iter().enumerate() {
+                let mut folded = match run.take() {
+                    Some(mut older) => {
+                        fold(&mut older, version, &self.versions);
+                        older
+                    }
+                    None => version,
+                };
+                if index == last_index || pinned.contains_key(&folded.checkpoint) {
+                    // Nothing is left below to remove keys from
+                    if self.versions.is_empty() || folded.detached {
+                        folded.removed_keys.clear();
+                    }
+                    folded.retired = index != last_index;
+                    self.versions.push(folded);
+                } else {
+                    run = Some(folded);
+                }
+            }
+            self.versions.extend(current);
+            drop(pinned);
+            if let Some(budget) = self.budget.as_mut() {
+                budget.reset();
+            }
+            self.enforce_budget();
+            self.debug_check();
+        }
+        self.get_last_checkpoint()
+    }
+
+    /// Pins the checkpoint to read its state by [`RollbackMap::get_at`].
+    /// Returns `None` if there is no such checkpoint.
+    ///
+    /// While the returned guard is alive, the checkpoint cannot be rolled back over,
+    /// and its layer is not folded by [`RollbackMap::prune`].
+    /// The guard does not borrow the map and can be sent to another thread.
+    ///
+    /// [`SyncRollbackMap`](crate::sync::SyncRollbackMap) has no pins, as its readers
+    /// hold snapshots which keep their state alive by themselves.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// let first_checkpoint = map.checkpoint().unwrap();
+    /// let pin = map.pin(first_checkpoint).unwrap();
+    /// map.insert(1, "b");
+    /// let second_checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "c");
+    ///
+    /// assert_eq!(map.prune(), Some(second_checkpoint));
+    /// assert_eq!(map.get_checkpoints_count(), 1);
+    /// assert_eq!(map.get_at(&pin, &1), Some(&"a"));
+    /// assert_eq!(map.get(&1), Some(&"c"));
+    ///
+    /// drop(pin);
+    /// map.prune();
+    /// assert!(map.rollback(second_checkpoint));
+    /// assert_eq!(map.get(&1), Some(&"b"));
+    /// ```
+    pub fn pin(&self, checkpoint: u32) -> Option<PinGuard> {
+        self.checkpoint_index(checkpoint)?;
+        *self.pins.lock().entry(checkpoint).or_insert(0) += 1;
+        Some(PinGuard {
+            registry: Arc::clone(&self.pins.registry),
+            checkpoint,
+        })
+    }
+
+    // Same as prune, the layers of the checkpoints are kept as if they were pinned.
+    pub(crate) fn prune_pinned(&mut self, pinned: &[u32]) -> Option<u32> {
+        let guards: Vec<PinGuard> = pinned
+            .iter()
+            .map(|&checkpoint| {
+                *self.pins.lock().entry(checkpoint).or_insert(0) += 1;
+                PinGuard {
+                    registry: Arc::clone(&self.pins.registry),
+                    checkpoint,
+                }
+            })
+            .collect();
+        let checkpoint = self.prune();
+        drop(guards);
+        checkpoint
+    }
+
+    /// Returns a reference to the value corresponding to the key
+    /// as of the pinned checkpoint.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the pin was taken on another map.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// let pin = map.pin(checkpoint).unwrap();
+    /// map.remove(&1);
+    /// map.insert(2, "b");
+    /// assert_eq!(map.get_at(&pin, &1), Some(&"a"));
+    /// assert_eq!(map.get_at(&pin, &2), None);
+    /// ```
+    pub fn get_at<Q>(&self, pin: &PinGuard, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        assert!(
+            Arc::ptr_eq(&pin.registry, &self.pins.registry),
+            "the checkpoint is pinned on another map"
+        );
+        let saved = &self.versions[..self.versions.len() - 1];
+        let index = saved
+            .binary_search_by_key(&pin.checkpoint, |version| version.checkpoint)
+            .expect("a pinned checkpoint is always present");
+        get_key_value(&self.versions[..=index], key).map(|(_, v)| v)
+    }
+
+    /// Returns `true` if both maps have the same visible content and
+    /// the same checkpoints with the same changes recorded in them.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    /// ```
+    /// use crate::rollbackmap::RollbackMap;
+    ///
+    /// let mut a = RollbackMap::new();
+    /// a.insert(1, "a");
+    /// let mut b = a.clone();
+    /// assert!(a.history_eq(&b));
+    ///
+    /// a.checkpoint();
+    /// assert_eq!(a, b);
+    /// assert!(!a.history_eq(&b));
+    /// ```
+    pub fn history_eq(&self, other: &Self) -> bool
+    where
+        V: PartialEq,
+    {
+        self.versions == other.versions
+    }
+
+    // Merges the versions into the visible key-value pairs.
+    fn visible(&self) -> BTreeMap<&K, &V> {
+        visible(&self.versions)
+    }
+
+    // Same as visible, but consumes the versions.
+    fn into_visible(mut self) -> BTreeMap<K, V> {
+        let base = self.base_index();
+        let mut visible = BTreeMap::new();
+        for version in self.versions.drain(base..) {
+            for key in &version.removed_keys {
+                visible.remove(key);
+            }
+            visible.extend(version.data);
+        }
+        visible
+    }
+
+    // Index of the version saved by the checkpoint.
+    // The current version is not a checkpoint yet, so it is never found,
+    // neither are the retired versions.
+    pub(crate) fn checkpoint_index(&self, checkpoint: u32) -> Option<usize> {
+        let saved = &self.versions[..self.versions.len() - 1];
+        saved
+            .binary_search_by_key(&checkpoint, |version| version.checkpoint)
+            .ok()
+            .filter(|&index| !saved[index].retired)
+    }
+
+    // Same as checkpoint_index, but only if none of the later checkpoints is pinned.
+    pub(crate) fn rollback_index(&self, checkpoint: u32) -> Option<usize> {
+        let index = self.checkpoint_index(checkpoint)?;
+        if self.pins.is_pinned_after(checkpoint) {
+            return None;
         }
-        return self.get_last_checkpoint();
+        Some(index)
+    }
+
+    // Number of the retired versions, they are the oldest ones.
+    fn retired_count(&self) -> usize {
+        self.versions
+            .iter()
+            .take_while(|version| version.retired)
+            .count()
+    }
+
+    // Index of the oldest version that is visible.
+    fn base_index(&self) -> usize {
+        base_index(&self.versions)
+    }
+}
+
+// Keys touched by the discarded versions, sorted.
+pub(crate) fn reverted_keys<'a, K: Ord, V>(
+    kept: &'a [VersionState<K, V>],
+    discarded: &'a [VersionState<K, V>],
+) -> BTreeSet<&'a K> {
+    let mut keys = BTreeSet::new();
+    for version in discarded {
+        keys.extend(version.data.keys());
+        keys.extend(version.removed_keys.iter());
     }
+    // A clear hid all the keys of the checkpoint
+    if discarded.iter().any(|version| version.detached) {
+        keys.extend(visible(kept).into_keys());
+    }
+    keys
+}
+
+// Splits the versions into the current one and the checkpointed ones.
+pub(crate) fn split_last_mut<K, V>(
+    versions: &mut [VersionState<K, V>],
+) -> (&mut VersionState<K, V>, &[VersionState<K, V>]) {
+    let (last, older) = versions
+        .split_last_mut()
+        .expect("the current version is always present");
+    (last, older)
 }
+
+fn visible<K: Ord, V>(versions: &[VersionState<K, V>]) -> BTreeMap<&K, &V> {
+    let mut visible = BTreeMap::new();
+    for version in &versions[base_index(versions)..] {
+        for key in &version.removed_keys {
+            visible.remove(key);
+        }
+        visible.extend(version.data.iter());
+    }
+    visible
+}
+
+pub(crate) fn base_index<K, V>(versions: &[VersionState<K, V>]) -> usize {
+    versions
+        .iter()
+        .rposition(|version| version.detached)
+        .unwrap_or(0)
+}
+
+// Merges the newer version into the older one, the versions below the older one
+// tell whether a removed key still has to be hidden.
+// The merged version has the newer version's checkpoint.
+pub(crate) fn fold<K: Ord, V>(
+    older: &mut VersionState<K, V>,
+    newer: VersionState<K, V>,
+    below: &[VersionState<K, V>],
+) {
+    if newer.detached {
+        *older = newer;
+        return;
+    }
+    for key in newer.removed_keys {
+        // A key added by the older version is not visible below it
+        if older.data.remove(&key).is_none()
+            || (!older.detached && get_key_value(below, &key).is_some())
+        {
+            older.removed_keys.insert(key);
+        }
+    }
+    for (key, value) in newer.data {
+        older.removed_keys.remove(&key);
+        older.data.insert(key, value);
+    }
+    older.checkpoint = newer.checkpoint;
+    older.values_count = newer.values_count;
+    older.retired = newer.retired;
+}
+
+// Retired versions have to be the oldest ones, and the last checkpoint
+// as well as the current version are never retired.
+pub(crate) fn retired_versions_valid<K, V>(versions: &[VersionState<K, V>]) -> bool {
+    let retired = versions
+        .iter()
+        .take_while(|version| version.retired)
+        .count();
+    retired + 2 <= versions.len().max(2) && versions[retired..].iter().all(|v| !v.retired)
+}
+
+/// Keeps a checkpoint of a [`RollbackMap`] pinned until dropped.
+///
+/// See [`RollbackMap::pin`].
+#[derive(Debug)]
+pub struct PinGuard {
+    registry: Arc<Mutex<BTreeMap<u32, usize>>>,
+    checkpoint: u32,
+}
+
+impl PinGuard {
+    /// Returns the pinned checkpoint.
+    pub fn checkpoint(&self) -> u32 {
+        self.checkpoint
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        let mut pins = lock(&self.registry);
+        if let btree_map::Entry::Occupied(mut entry) = pins.entry(self.checkpoint) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
+
+// Pinned checkpoints with the number of guards for each of them.
+#[derive(Debug, Default)]
+pub(crate) struct Pins {
+    registry: Arc<Mutex<BTreeMap<u32, usize>>>,
+}
+
+impl Pins {
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u32, usize>> {
+        lock(&self.registry)
+    }
+
+    pub(crate) fn is_pinned(&self, checkpoint: u32) -> bool {
+        self.lock().contains_key(&checkpoint)
+    }
+
+    // Returns true if a checkpoint later than the given one is pinned.
+    pub(crate) fn is_pinned_after(&self, checkpoint: u32) -> bool {
+        self.lock()
+            .range((Bound::Excluded(checkpoint), Bound::Unbounded))
+            .next()
+            .is_some()
+    }
+
+    pub(crate) fn pinned(&self) -> Vec<u32> {
+        self.lock().keys().copied().collect()
+    }
+}
+
+impl Clone for Pins {
+    // The guards keep pinned the checkpoints of the original map only.
+    fn clone(&self) -> Self {
+        Pins::default()
+    }
+}
+
+// The registry is consistent even if a thread panicked holding the lock.
+fn lock(registry: &Mutex<BTreeMap<u32, usize>>) -> MutexGuard<'_, BTreeMap<u32, usize>> {
+    match registry.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+// Result of looking a key up in a range of versions.
+pub(crate) enum Lookup<'a, K, V> {
+    Found(&'a K, &'a V),
+    // The key was removed or cleared in one of the versions.
+    Hidden,
+    // None of the versions knows the key, the older versions decide.
+    Missing,
+}
+
+// Looks the key up in the versions starting from the newest one.
+pub(crate) fn lookup<'a, K, V, Q>(versions: &'a [VersionState<K, V>], key: &Q) -> Lookup<'a, K, V>
+where
+    K: Borrow<Q> + Ord,
+    Q: Ord + ?Sized,
+{
+    for version in versions.iter().rev() {
+        if let Some((found_key, found_value)) = version.data.get_key_value(key) {
+            return Lookup::Found(found_key, found_value);
+        }
+        if version.removed_keys.contains(key) || version.detached {
+            return Lookup::Hidden;
+        }
+    }
+    Lookup::Missing
+}
+
+pub(crate) fn get_key_value<'a, K, V, Q>(
+    versions: &'a [VersionState<K, V>],
+    key: &Q,
+) -> Option<(&'a K, &'a V)>
+where
+    K: Borrow<Q> + Ord,
+    Q: Ord + ?Sized,
+{
+    match lookup(versions, key) {
+        Lookup::Found(found_key, found_value) => Some((found_key, found_value)),
+        Lookup::Hidden | Lookup::Missing => None,
+    }
+}
+
+impl<K: Ord, V> FromIterator<(K, V)> for RollbackMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut map = RollbackMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+/// Extending inserts all the entries into the current (not yet checkpointed) version.
+impl<K: Ord, V> Extend<(K, V)> for RollbackMap<K, V> {
+    fn extend<I: IntoIterator<Item = (K, V)>>(&mut self, iter: I) {
+        for (key, value) in iter {
+            self.replace(key, value);
+        }
+    }
+}
+
+impl<'a, K: Ord + Copy, V: Copy> Extend<(&'a K, &'a V)> for RollbackMap<K, V> {
+    fn extend<I: IntoIterator<Item = (&'a K, &'a V)>>(&mut self, iter: I) {
+        self.extend(iter.into_iter().map(|(&key, &value)| (key, value)));
+    }
+}
+
+impl<K, Q, V> Index<&Q> for RollbackMap<K, V>
+where
+    K: Borrow<Q> + Ord,
+    Q: Ord + ?Sized,
+{
+    type Output = V;
+
+    /// Returns a reference to the value corresponding to the supplied key.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the key is not present in the map.
+    fn index(&self, key: &Q) -> &V {
+        self.get(key).expect("no entry found for key")
+    }
+}
+
+impl<K: Ord, V> IntoIterator for RollbackMap<K, V> {
+    type Item = (K, V);
+    type IntoIter = IntoIter<K, V>;
+
+    /// Consumes the map into an iterator over its visible entries, sorted by key.
+    fn into_iter(self) -> IntoIter<K, V> {
+        IntoIter {
+            inner: self.into_visible().into_iter(),
+        }
+    }
+}
+
+impl<'a, K: Ord, V> IntoIterator for &'a RollbackMap<K, V> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V>;
+
+    fn into_iter(self) -> Iter<'a, K, V> {
+        self.iter()
+    }
+}
+
+/// An iterator over the visible entries of a `RollbackMap`, sorted by key.
+///
+/// This `struct` is created by the [`RollbackMap::iter`] method.
+#[derive(Debug)]
+pub struct Iter<'a, K, V> {
+    inner: btree_map::IntoIter<&'a K, &'a V>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+impl<'a, K, V> ExactSizeIterator for Iter<'a, K, V> {}
+
+/// An owning iterator over the visible entries of a `RollbackMap`, sorted by key.
+///
+/// This `struct` is created by the `into_iter` method on `RollbackMap`.
+#[derive(Debug)]
+pub struct IntoIter<K, V> {
+    inner: btree_map::IntoIter<K, V>,
+}
+
+impl<K, V> Iterator for IntoIter<K, V> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V> DoubleEndedIterator for IntoIter<K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+impl<K, V> ExactSizeIterator for IntoIter<K, V> {}
@@ -0,0 +1,237 @@
+//! Stable binary snapshot format of a [`RollbackMap`].
+//!
+//! Unlike the serde support, this format is versioned and documented,
+//! so snapshots written by one version of the crate stay readable by the later ones.
+//! Keys and values are encoded by caller-supplied [`Codec`]s.
+//!
+//! All integers are little-endian, lengths are `u64`.
+//!
+//! | Field          | Size     | Description                                        |
+//! |----------------|----------|----------------------------------------------------|
+//! | magic          | 4        | [`MAGIC`], `b"RBMP"`                               |
+//! | format version | 2        | [`FORMAT_VERSION`]                                 |
+//! | versions count | 4        | number of version blocks that follow, at least 1   |
+//! | version blocks | variable | from the oldest version to the current one         |
+//! | checksum       | 4        | CRC-32 (IEEE) of all the preceding bytes           |
+//!
+//! Each version block:
+//!
+//! | Field          | Size     | Description                                        |
+//! |----------------|----------|----------------------------------------------------|
+//! | checkpoint     | 4        | checkpoint id, strictly increasing between blocks  |
+//! | flags          | 1        | bit 0 is set if the version was detached by clear, |
+//! |                |          | bit 1 if the checkpoint was pruned while pinned    |
+//! | values count   | 8        | number of visible values in this version           |
+//! | entries count  | 8        | number of key-value pairs that follow              |
+//! | entries        | variable | key and value, sorted by key                       |
+//! | removed count  | 8        | number of removed keys that follow                 |
+//! | removed keys   | variable | keys removed from the older versions, sorted       |
+//!
+//! Format version 2 added the flag of the pruned checkpoints, whose versions
+//! have to be the oldest ones.
+use crate::codec::{
+    invalid_data, read_len, read_u32, read_u8, write_len, write_u32, write_u8, ChecksumReader,
+    ChecksumWriter, Codec, LittleEndian,
+};
+use crate::rollbackmap::{retired_versions_valid, RollbackMap, VersionState};
+use std::io::{self, Read, Write};
+
+/// Magic bytes every snapshot starts with.
+pub const MAGIC: [u8; 4] = *b"RBMP";
+
+/// Version of the snapshot format written by this crate.
+///
+/// Snapshots of this or any older format version can be read.
+pub const FORMAT_VERSION: u16 = 2;
+
+const DETACHED_FLAG: u8 = 1;
+const RETIRED_FLAG: u8 = 2;
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Writes the map with all of its checkpoints in the [snapshot format](crate::snapshot).
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::codec::{LengthPrefixed, LittleEndian};
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1u32, "a".to_owned());
+    /// let checkpoint = map.checkpoint().unwrap();
+    /// map.insert(2, "b".to_owned());
+    ///
+    /// let mut bytes = Vec::new();
+    /// map.write_to(&mut bytes, &LittleEndian, &LengthPrefixed).unwrap();
+    ///
+    /// let mut restored =
+    ///     RollbackMap::read_from(bytes.as_slice(), &LittleEndian, &LengthPrefixed).unwrap();
+    /// assert!(restored.history_eq(&map));
+    /// assert!(restored.rollback(checkpoint));
+    /// assert_eq!(restored.len(), 1);
+    /// ```
+    pub fn write_to<W, KC, VC>(&self, writer: W, key_codec: &KC, value_codec: &VC) -> io::Result<()>
+    where
+        W: Write,
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        let mut writer = ChecksumWriter::new(writer);
+        writer.write_all(&MAGIC)?;
+        LittleEndian.encode(&FORMAT_VERSION, &mut writer)?;
+        write_u32(&mut writer, self.versions.len() as u32)?;
+        for version in &self.versions {
+            write_version(&mut writer, version, key_codec, value_codec)?;
+        }
+        let checksum = writer.crc.finish();
+        write_u32(&mut writer.inner, checksum)?;
+        writer.inner.flush()
+    }
+
+    /// Reads a map written by [`RollbackMap::write_to`].
+    ///
+    /// Returns an error of the `InvalidData` kind if the data is not a valid snapshot,
+    /// has a newer format version or does not match the checksum.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::codec::LittleEndian;
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1u32, 10u64);
+    /// let mut bytes = Vec::new();
+    /// map.write_to(&mut bytes, &LittleEndian, &LittleEndian).unwrap();
+    ///
+    /// bytes[8] ^= 1;
+    /// let corrupted: std::io::Result<RollbackMap<u32, u64>> =
+    ///     RollbackMap::read_from(bytes.as_slice(), &LittleEndian, &LittleEndian);
+    /// assert!(corrupted.is_err());
+    /// ```
+    pub fn read_from<R, KC, VC>(reader: R, key_codec: &KC, value_codec: &VC) -> io::Result<Self>
+    where
+        R: Read,
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        let mut reader = ChecksumReader::new(reader);
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not a RollbackMap snapshot"));
+        }
+        let format_version: u16 = LittleEndian.decode(&mut reader)?;
+        if format_version == 0 || format_version > FORMAT_VERSION {
+            return Err(invalid_data("unsupported snapshot format version"));
+        }
+
+        let count = read_u32(&mut reader)?;
+        if count == 0 {
+            return Err(invalid_data("snapshot has no versions"));
+        }
+        let mut versions: Vec<VersionState<K, V>> = Vec::new();
+        for _ in 0..count {
+            let version = read_version(&mut reader, format_version, key_codec, value_codec)?;
+            if let Some(previous) = versions.last() {
+                if previous.checkpoint >= version.checkpoint {
+                    return Err(invalid_data(
+                        "snapshot checkpoints are not strictly increasing",
+                    ));
+                }
+            }
+            versions.push(version);
+        }
+        if !retired_versions_valid(&versions) {
+            return Err(invalid_data(
+                "snapshot pruned checkpoints are not the oldest ones",
+            ));
+        }
+
+        let checksum = reader.crc.finish();
+        if read_u32(&mut reader.inner)? != checksum {
+            return Err(invalid_data("snapshot checksum mismatch"));
+        }
+        let map = RollbackMap::from_versions(versions);
+        if let Err(inconsistency) = map.validate() {
+            return Err(invalid_data(&format!(
+                "snapshot is inconsistent: {}",
+                inconsistency
+            )));
+        }
+        Ok(map)
+    }
+}
+
+pub(crate) fn write_version<K, V, KC, VC>(
+    writer: &mut dyn Write,
+    version: &VersionState<K, V>,
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<()>
+where
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    write_u32(writer, version.checkpoint)?;
+    let mut flags = 0;
+    if version.detached {
+        flags |= DETACHED_FLAG;
+    }
+    if version.retired {
+        flags |= RETIRED_FLAG;
+    }
+    write_u8(writer, flags)?;
+    write_len(writer, version.values_count)?;
+    write_len(writer, version.data.len())?;
+    for (key, value) in &version.data {
+        key_codec.encode(key, writer)?;
+        value_codec.encode(value, writer)?;
+    }
+    write_len(writer, version.removed_keys.len())?;
+    for key in &version.removed_keys {
+        key_codec.encode(key, writer)?;
+    }
+    Ok(())
+}
+
+// Reads a version block of the format version.
+pub(crate) fn read_version<K, V, KC, VC>(
+    reader: &mut dyn Read,
+    format_version: u16,
+    key_codec: &KC,
+    value_codec: &VC,
+) -> io::Result<VersionState<K, V>>
+where
+    K: Ord,
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    let checkpoint = read_u32(reader)?;
+    let flags = read_u8(reader)?;
+    let known_flags = match format_version {
+        1 => DETACHED_FLAG,
+        _ => DETACHED_FLAG | RETIRED_FLAG,
+    };
+    if flags & !known_flags != 0 {
+        return Err(invalid_data("unknown version flags"));
+    }
+    let values_count = read_len(reader)?;
+    let mut version = VersionState::new(checkpoint, values_count);
+    version.detached = flags & DETACHED_FLAG != 0;
+    version.retired = flags & RETIRED_FLAG != 0;
+
+    for _ in 0..read_len(reader)? {
+        let key = key_codec.decode(reader)?;
+        let value = value_codec.decode(reader)?;
+        version.data.insert(key, value);
+    }
+    for _ in 0..read_len(reader)? {
+        version.removed_keys.insert(key_codec.decode(reader)?);
+    }
+    Ok(version)
+}
@@ -0,0 +1,526 @@
+//! [`RollbackMap`] that spills its oldest checkpoints to a local file.
+//!
+//! [`SpillingRollbackMap`] keeps only the newest versions (the hot window) in memory.
+//! When a checkpoint makes the window longer than the configured limit, the oldest
+//! versions are appended to the spill file in the [snapshot](crate::snapshot)
+//! version block format, so the memory scales with the hot window only.
+//!
+//! The spilled versions are read back when a key is not found in the hot window,
+//! and paged in for good when the map is rolled back to one of their checkpoints.
+//! A lookup that misses the hot window reads the spilled versions newest first
+//! until the key is found, so keep the window long enough for the frequently read keys.
+//! Nothing read back by a lookup is kept: every miss decodes whole spilled versions
+//! from the file again, all of them for a key the map does not have.
+//! The lookups from several threads take turns reading the file.
+use crate::codec::Codec;
+use crate::events::Event;
+use crate::rollbackmap::{
+    lookup, reverted_keys, split_last_mut, Lookup, RollbackMap, VersionState,
+};
+use crate::snapshot::{read_version, write_version, FORMAT_VERSION};
+use core::borrow::Borrow;
+use std::borrow::Cow;
+use std::collections::btree_map::Entry;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// A [`RollbackMap`] that keeps only its newest versions in memory.
+///
+/// Operations that may need to read the spilled versions return an `io::Result`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::codec::LittleEndian;
+/// use rollbackmap::spill::SpillingRollbackMap;
+///
+/// let path = std::env::temp_dir().join(format!("rollbackmap-spill-doc-{}", std::process::id()));
+/// let mut map = SpillingRollbackMap::new(&path, 2, LittleEndian, LittleEndian).unwrap();
+/// map.insert(1u32, 10u64).unwrap();
+/// let first = map.checkpoint().unwrap().unwrap();
+/// for value in 11..20 {
+///     map.insert(1, value).unwrap();
+///     map.checkpoint().unwrap();
+/// }
+/// assert_eq!(map.hot_checkpoints_count(), 1);
+/// assert_eq!(map.spilled_checkpoints_count(), 9);
+///
+/// assert!(map.rollback(first).unwrap());
+/// assert_eq!(map.get(&1).unwrap().as_deref(), Some(&10));
+/// ```
+#[derive(Debug)]
+pub struct SpillingRollbackMap<K: Ord, V, KC, VC> {
+    hot: RollbackMap<K, V>,
+    cold: ColdVersions<KC, VC>,
+    hot_limit: usize,
+}
+
+impl<K, V, KC, VC> SpillingRollbackMap<K, V, KC, VC>
+where
+    K: Ord,
+    KC: Codec<K>,
+    VC: Codec<V>,
+{
+    /// Makes a new, empty map spilling to the file at the path.
+    ///
+    /// The file is created or truncated, and removed when the map is dropped.
+    /// `hot_limit` is the number of versions kept in memory, including the current one;
+    /// it is raised to 2 if lower, so the last checkpoint always stays in memory.
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        hot_limit: usize,
+        key_codec: KC,
+        value_codec: VC,
+    ) -> io::Result<Self> {
+        Self::from_map(RollbackMap::new(), path, hot_limit, key_codec, value_codec)
+    }
+
+    /// Wraps the map, spilling its versions beyond the hot window right away.
+    /// See [`SpillingRollbackMap::new`].
+    ///
+    /// The memory budget of the map is removed, the spill file takes its place.
+    /// The observers of the map are kept and notified of the changes.
+    pub fn from_map<P: AsRef<Path>>(
+        map: RollbackMap<K, V>,
+        path: P,
+        hot_limit: usize,
+        key_codec: KC,
+        value_codec: VC,
+    ) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        let mut map = SpillingRollbackMap {
+            hot: RollbackMap {
+                budget: None,
+                partial: true,
+                ..map
+            },
+            cold: ColdVersions {
+                file: Mutex::new(file),
+                path,
+                layers: Vec::new(),
+                end: 0,
+                key_codec,
+                value_codec,
+            },
+            hot_limit: hot_limit.max(2),
+        };
+        map.spill()?;
+        Ok(map)
+    }
+
+    /// Inserts a key-value pair into the map.
+    /// See [`RollbackMap::insert`].
+    pub fn insert(&mut self, key: K, value: V) -> io::Result<Option<V>>
+    where
+        V: Clone,
+    {
+        let (last, older) = split_last_mut(&mut self.hot.versions);
+        let entry = match last.data.entry(key) {
+            Entry::Occupied(mut entry) => {
+                let previous = entry.insert(value);
+                self.hot.observers.notify(&Event::Updated(entry.key()));
+                self.hot.debug_check();
+                return Ok(Some(previous));
+            }
+            Entry::Vacant(entry) => entry,
+        };
+
+        let shadowed = if last.removed_keys.remove(entry.key()) || last.detached {
+            None
+        } else {
+            match lookup(older, entry.key()) {
+                Lookup::Found(_, found_value) => Some(found_value.clone()),
+                Lookup::Hidden => None,
+                Lookup::Missing => self.cold.get_key_value(entry.key())?.map(|(_, v)| v),
+            }
+        };
+
+        let entry = entry.insert_entry(value);
+        self.hot.observers.notify(&match shadowed {
+            Some(_) => Event::Updated(entry.key()),
+            None => {
+                last.values_count += 1;
+                Event::Inserted(entry.key())
+            }
+        });
+        self.hot.debug_check();
+        Ok(shadowed)
+    }
+
+    /// Removes a key from the map.
+    /// See [`RollbackMap::remove`].
+    pub fn remove<Q>(&mut self, key: &Q) -> io::Result<Option<V>>
+    where
+        K: Borrow<Q> + Clone,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        let (last, older) = split_last_mut(&mut self.hot.versions);
+        if last.data.contains_key(key) {
+            // The value kept by a checkpoint must stay hidden
+            let shadowed = !last.detached
+                && match lookup(older, key) {
+                    Lookup::Found(..) => true,
+                    Lookup::Hidden => false,
+                    Lookup::Missing => self.cold.get_key_value(key)?.is_some(),
+                };
+            let (removed_key, value) = last.data.remove_entry(key).expect("the key is present");
+            last.values_count -= 1;
+            if shadowed {
+                last.removed_keys.insert(removed_key);
+                let removed_key = last.removed_keys.get(key).expect("the key is removed");
+                self.hot.observers.notify(&Event::Removed(removed_key));
+            } else {
+                self.hot.observers.notify(&Event::Removed(&removed_key));
+            }
+            self.hot.debug_check();
+            return Ok(Some(value));
+        }
+        if last.removed_keys.contains(key) || last.detached {
+            return Ok(None);
+        }
+
+        let found = match lookup(older, key) {
+            Lookup::Found(found_key, found_value) => Some((found_key.clone(), found_value.clone())),
+            Lookup::Hidden => None,
+            Lookup::Missing => self.cold.get_key_value(key)?,
+        };
+        let observers = &mut self.hot.observers;
+        let removed = found.map(|(found_key, found_value)| {
+            last.values_count -= 1;
+            last.removed_keys.insert(found_key);
+            let removed_key = last.removed_keys.get(key).expect("the key is removed");
+            observers.notify(&Event::Removed(removed_key));
+            found_value
+        });
+        self.hot.debug_check();
+        Ok(removed)
+    }
+
+    /// Clears the map.
+    /// See [`RollbackMap::clear`].
+    pub fn clear(&mut self) {
+        self.hot.clear();
+    }
+
+    /// Returns `true` if the map contains a value for the specified key.
+    /// See [`RollbackMap::contains_key`].
+    ///
+    /// A key missing from the hot window is looked up in the spill file like by
+    /// [`SpillingRollbackMap::get`].
+    pub fn contains_key<Q>(&self, key: &Q) -> io::Result<bool>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        Ok(match lookup(&self.hot.versions, key) {
+            Lookup::Found(..) => true,
+            Lookup::Hidden => false,
+            Lookup::Missing => self.cold.get_key_value(key)?.is_some(),
+        })
+    }
+
+    /// Returns the value corresponding to the key.
+    ///
+    /// The value is borrowed if it lives in the hot window,
+    /// a value read from the spill file is returned owned.
+    ///
+    /// A key missing from the hot window costs decoding the spilled versions,
+    /// newest first, down to the one that has the key or hides it,
+    /// and every one of them if no version knows the key.
+    pub fn get<Q>(&self, key: &Q) -> io::Result<Option<Cow<'_, V>>>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+        V: Clone,
+    {
+        Ok(match lookup(&self.hot.versions, key) {
+            Lookup::Found(_, found_value) => Some(Cow::Borrowed(found_value)),
+            Lookup::Hidden => None,
+            Lookup::Missing => self.cold.get_key_value(key)?.map(|(_, v)| Cow::Owned(v)),
+        })
+    }
+
+    /// Returns the number of elements in the map.
+    pub fn len(&self) -> usize {
+        self.hot.len()
+    }
+
+    /// Returns `true` if the map contains no elements.
+    pub fn is_empty(&self) -> bool {
+        self.hot.is_empty()
+    }
+
+    /// Creates a checkpoint and spills the versions that left the hot window.
+    /// See [`RollbackMap::checkpoint`].
+    ///
+    /// If spilling fails, the checkpoint is still created,
+    /// and the spilling is retried by the next checkpoint.
+    pub fn checkpoint(&mut self) -> io::Result<Option<u32>> {
+        let checkpoint = self.hot.checkpoint();
+        self.spill()?;
+        Ok(checkpoint)
+    }
+
+    /// Returns last created checkpoint if any.
+    /// See [`RollbackMap::get_last_checkpoint`].
+    pub fn get_last_checkpoint(&self) -> Option<u32> {
+        self.hot.get_last_checkpoint()
+    }
+
+    /// Returns checkpoint before the last one saved.
+    /// See [`RollbackMap::get_prev_checkpoint`].
+    pub fn get_prev_checkpoint(&self) -> Option<u32> {
+        if self.hot.versions.len() > 2 {
+            return self.hot.get_prev_checkpoint();
+        }
+        self.cold
+            .layers
+            .last()
+            .filter(|layer| !layer.retired)
+            .map(|layer| layer.checkpoint)
+    }
+
+    /// Returns checkpoint count, both in memory and spilled.
+    pub fn get_checkpoints_count(&self) -> usize {
+        self.spilled_checkpoints_count() + self.hot.get_checkpoints_count()
+    }
+
+    /// Returns the number of checkpoints kept in memory.
+    pub fn hot_checkpoints_count(&self) -> usize {
+        self.hot.get_checkpoints_count()
+    }
+
+    /// Returns the number of checkpoints spilled to the file.
+    pub fn spilled_checkpoints_count(&self) -> usize {
+        self.cold
+            .layers
+            .iter()
+            .filter(|layer| !layer.retired)
+            .count()
+    }
+
+    /// Rollbacks to saved checkpoint.
+    /// See [`RollbackMap::rollback`].
+    ///
+    /// Rolling back to a spilled checkpoint pages its version back in
+    /// and cuts the newer spilled versions off the file.
+    pub fn rollback(&mut self, checkpoint: u32) -> io::Result<bool> {
+        if let Some(index) = self.hot.checkpoint_index(checkpoint) {
+            let cleared = self.hot.versions[index + 1..]
+                .iter()
+                .any(|version| version.detached);
+            if !cleared || self.hot.observers.is_empty() {
+                return Ok(self.hot.rollback(checkpoint));
+            }
+            // The keys hidden by the clear are reverted too, the spilled versions
+            // are paged in for the observers to get them.
+            let mut versions = Vec::with_capacity(self.cold.layers.len() + self.hot.versions.len());
+            for layer in 0..self.cold.layers.len() {
+                versions.push(self.cold.read(layer)?);
+            }
+            let spilled = versions.len();
+            versions.append(&mut self.hot.versions);
+            self.hot.versions = versions;
+            let rollback = self.hot.rollback(checkpoint);
+            self.hot.versions.drain(..spilled);
+            return Ok(rollback);
+        }
+        let index = match self
+            .cold
+            .layers
+            .binary_search_by_key(&checkpoint, |layer| layer.checkpoint)
+        {
+            Ok(index) if !self.cold.layers[index].retired => index,
+            _ => return Ok(false),
+        };
+        if self.hot.pins.is_pinned_after(checkpoint) {
+            return Ok(false);
+        }
+
+        let version = self.cold.read(index)?;
+        let next_checkpoint = match self.cold.layers.get(index + 1) {
+            Some(layer) => layer.checkpoint,
+            None => self.hot.versions[0].checkpoint,
+        };
+        // The reverted keys are read back from the discarded versions for the observers only
+        let mut versions = Vec::new();
+        if !self.hot.observers.is_empty() {
+            for layer in 0..self.cold.layers.len() {
+                versions.push(self.cold.read(layer)?);
+            }
+        }
+        self.cold.truncate(index)?;
+        let values_count = version.values_count;
+        let hot = mem::replace(
+            &mut self.hot.versions,
+            vec![version, VersionState::new(next_checkpoint, values_count)],
+        );
+        self.hot.debug_check();
+        if !versions.is_empty() {
+            versions.extend(hot);
+            let (kept, discarded) = versions.split_at(index + 1);
+            let reverted_keys = reverted_keys(kept, discarded).into_iter().collect();
+            self.hot.observers.notify(&Event::RolledBack {
+                to: checkpoint,
+                reverted_keys,
+            });
+        }
+        Ok(true)
+    }
+
+    /// Deletes all the checkpoints except the last one.
+    /// See [`RollbackMap::prune`].
+    ///
+    /// The spilled versions are paged in to be folded into the last checkpoint,
+    /// and the spill file is emptied.
+    pub fn prune(&mut self) -> io::Result<Option<u32>> {
+        let mut versions = Vec::with_capacity(self.cold.layers.len() + self.hot.versions.len());
+        for index in 0..self.cold.layers.len() {
+            versions.push(self.cold.read(index)?);
+        }
+        self.cold.truncate(0)?;
+        versions.append(&mut self.hot.versions);
+        self.hot.versions = versions;
+        let checkpoint = self.hot.prune();
+        self.spill()?;
+        Ok(checkpoint)
+    }
+
+    /// Pages all the spilled versions in and returns the whole map.
+    pub fn into_map(mut self) -> io::Result<RollbackMap<K, V>> {
+        let mut versions = Vec::with_capacity(self.get_checkpoints_count() + 1);
+        for index in 0..self.cold.layers.len() {
+            versions.push(self.cold.read(index)?);
+        }
+        versions.append(&mut self.hot.versions);
+        Ok(RollbackMap::from_versions(versions))
+    }
+
+    // Moves the oldest versions out of the hot window.
+    // A version leaves the memory only once it is written.
+    fn spill(&mut self) -> io::Result<()> {
+        while self.hot.versions.len() > self.hot_limit {
+            self.cold.push(&self.hot.versions[0])?;
+            self.hot.versions.remove(0);
+        }
+        Ok(())
+    }
+}
+
+// Versions spilled to the file, from the oldest one.
+// The file is locked for every read, as the lookups share its cursor.
+#[derive(Debug)]
+struct ColdVersions<KC, VC> {
+    file: Mutex<File>,
+    path: PathBuf,
+    layers: Vec<ColdLayer>,
+    // Offset right after the last version block.
+    end: u64,
+    key_codec: KC,
+    value_codec: VC,
+}
+
+#[derive(Debug)]
+struct ColdLayer {
+    checkpoint: u32,
+    retired: bool,
+    offset: u64,
+}
+
+impl<KC, VC> ColdVersions<KC, VC> {
+    fn push<K, V>(&mut self, version: &VersionState<K, V>) -> io::Result<()>
+    where
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        let mut file = lock(&self.file);
+        file.seek(SeekFrom::Start(self.end))?;
+        {
+            let mut writer = BufWriter::new(&mut *file);
+            write_version(&mut writer, version, &self.key_codec, &self.value_codec)?;
+            writer.flush()?;
+        }
+        let end = file.stream_position()?;
+
+        self.layers.push(ColdLayer {
+            checkpoint: version.checkpoint,
+            retired: version.retired,
+            offset: self.end,
+        });
+        self.end = end;
+        Ok(())
+    }
+
+    fn read<K, V>(&self, index: usize) -> io::Result<VersionState<K, V>>
+    where
+        K: Ord,
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        let mut file = lock(&self.file);
+        file.seek(SeekFrom::Start(self.layers[index].offset))?;
+        read_version(
+            &mut BufReader::new(&mut *file),
+            FORMAT_VERSION,
+            &self.key_codec,
+            &self.value_codec,
+        )
+    }
+
+    // Looks the key up in the versions starting from the newest one.
+    fn get_key_value<K, V, Q>(&self, key: &Q) -> io::Result<Option<(K, V)>>
+    where
+        K: Borrow<Q> + Ord,
+        Q: Ord + ?Sized,
+        KC: Codec<K>,
+        VC: Codec<V>,
+    {
+        for index in (0..self.layers.len()).rev() {
+            let mut version = self.read(index)?;
+            if let Some(key_value) = version.data.remove_entry(key) {
+                return Ok(Some(key_value));
+            }
+            if version.removed_keys.contains(key) || version.detached {
+                return Ok(None);
+            }
+        }
+        Ok(None)
+    }
+
+    // Drops the versions starting from the index.
+    fn truncate(&mut self, index: usize) -> io::Result<()> {
+        let end = match self.layers.get(index) {
+            Some(layer) => layer.offset,
+            None => return Ok(()),
+        };
+        lock(&self.file).set_len(end)?;
+        self.layers.truncate(index);
+        self.end = end;
+        Ok(())
+    }
+}
+
+fn lock(file: &Mutex<File>) -> MutexGuard<'_, File> {
+    match file.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+impl<KC, VC> Drop for ColdVersions<KC, VC> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,178 @@
+//! Memory and layer statistics of a [`RollbackMap`].
+//!
+//! [`RollbackMap::stats`] describes every layer of the map, from the oldest checkpoint
+//! to the current changes, and estimates the memory they hold,
+//! so that a growing rollback history can be noticed before it gets too large.
+use crate::rollbackmap::{RollbackMap, VersionState};
+use std::collections::BTreeSet;
+use std::mem::size_of;
+
+/// Statistics of a single layer of a [`RollbackMap`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayerStats {
+    /// Checkpoint of the layer, the one to be created next for the current layer.
+    pub checkpoint: u32,
+    /// Count of the entries inserted or updated in the layer.
+    pub entries: usize,
+    /// Count of the keys removed in the layer, but present in the older ones.
+    pub tombstones: usize,
+    /// Count of the layer entries hidden by the newer layers.
+    pub shadowed: usize,
+    /// Is set if the map was cleared in the layer.
+    pub detached: bool,
+    /// Is set if the checkpoint was pruned, but its layer is kept for a pin.
+    pub retired: bool,
+}
+
+/// Statistics of a [`RollbackMap`] returned by [`RollbackMap::stats`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stats {
+    /// Layers from the oldest one, the last one holds the changes since the last checkpoint.
+    pub layers: Vec<LayerStats>,
+    /// Count of the visible entries.
+    pub len: usize,
+    /// Count of the entries of all the layers hidden by the newer layers.
+    pub shadowed: usize,
+    /// Estimated size in bytes of the memory held by the layers.
+    ///
+    /// The estimate counts the keys, the values, and the layers themselves,
+    /// but not the B-tree nodes overhead, so the real footprint is larger.
+    pub heap_size: usize,
+}
+
+impl Stats {
+    /// Returns the count of the entries stored in all the layers, shadowed ones included.
+    pub fn entries(&self) -> usize {
+        self.layers.iter().map(|layer| layer.entries).sum()
+    }
+
+    /// Returns the count of the tombstones of all the layers.
+    pub fn tombstones(&self) -> usize {
+        self.layers.iter().map(|layer| layer.tombstones).sum()
+    }
+}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Returns statistics of the map layers.
+    ///
+    /// The heap size is estimated with `size_of` of the keys and the values,
+    /// use [`RollbackMap::stats_with`] if they own heap memory.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.insert(2, "b");
+    /// map.checkpoint();
+    /// map.insert(1, "c");
+    /// map.remove(&2);
+    ///
+    /// let stats = map.stats();
+    /// assert_eq!(stats.layers.len(), 2);
+    /// assert_eq!(stats.layers[0].entries, 2);
+    /// assert_eq!(stats.layers[0].shadowed, 2);
+    /// assert_eq!(stats.layers[1].entries, 1);
+    /// assert_eq!(stats.layers[1].tombstones, 1);
+    /// assert_eq!(stats.len, 1);
+    /// assert_eq!(stats.shadowed, 2);
+    /// ```
+    pub fn stats(&self) -> Stats {
+        self.stats_with(|_| 0, |_| 0)
+    }
+
+    /// Returns statistics of the map layers, estimating the heap size
+    /// with the given sizes of the memory owned by a key and by a value
+    /// in addition to their `size_of`.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map: RollbackMap<u32, String> = RollbackMap::new();
+    /// map.insert(1, "a".repeat(1000));
+    ///
+    /// let stats = map.stats_with(|_| 0, |value| value.capacity());
+    /// assert!(stats.heap_size > 1000);
+    /// assert!(map.stats().heap_size < 1000);
+    /// ```
+    pub fn stats_with<F, G>(&self, mut key_size: F, mut value_size: G) -> Stats
+    where
+        F: FnMut(&K) -> usize,
+        G: FnMut(&V) -> usize,
+    {
+        let mut layers = Vec::with_capacity(self.versions.len());
+        let spare = self.versions.capacity() - self.versions.len();
+        let mut heap_size = spare * size_of::<VersionState<K, V>>();
+        // Keys set by the newer layers, unless one of them is detached.
+        let mut newer_keys = BTreeSet::new();
+        let mut newer_detached = false;
+        for version in self.versions.iter().rev() {
+            let shadowed = if newer_detached {
+                version.data.len()
+            } else {
+                version
+                    .data
+                    .keys()
+                    .filter(|key| newer_keys.contains(key))
+                    .count()
+            };
+            heap_size += layer_size(version, &mut key_size, &mut value_size);
+
+            layers.push(LayerStats {
+                checkpoint: version.checkpoint,
+                entries: version.data.len(),
+                tombstones: version.removed_keys.len(),
+                shadowed,
+                detached: version.detached,
+                retired: version.retired,
+            });
+
+            if version.detached {
+                newer_detached = true;
+                newer_keys.clear();
+            }
+            if !newer_detached {
+                newer_keys.extend(version.data.keys());
+                newer_keys.extend(version.removed_keys.iter());
+            }
+        }
+        layers.reverse();
+
+        Stats {
+            shadowed: layers.iter().map(|layer| layer.shadowed).sum(),
+            layers,
+            len: self.len(),
+            heap_size,
+        }
+    }
+}
+
+// Estimated size of the memory held by the layer,
+// the sizes of the memory owned by the keys and the values are given.
+pub(crate) fn layer_size<K, V, F, G>(
+    version: &VersionState<K, V>,
+    key_size: &mut F,
+    value_size: &mut G,
+) -> usize
+where
+    F: FnMut(&K) -> usize,
+    G: FnMut(&V) -> usize,
+{
+    let mut size = size_of::<VersionState<K, V>>();
+    for (key, value) in &version.data {
+        size += size_of::<K>() + key_size(key);
+        size += size_of::<V>() + value_size(value);
+    }
+    for key in &version.removed_keys {
+        size += size_of::<K>() + key_size(key);
+    }
+    size
+}
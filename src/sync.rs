@@ -0,0 +1,199 @@
+//! Rollback map shared between a writer and many reader threads.
+//!
+//! [`SyncRollbackMap`] is owned by a single writer that mutates it like a
+//! [`PersistentRollbackMap`]. Every checkpoint publishes an immutable [`Snapshot`]
+//! of the committed state, which [`SyncReader`] handles can pick up from any thread.
+//! Snapshots share their nodes with the map, so publishing one is O(1),
+//! and a snapshot handed out stays valid whatever the writer does afterwards.
+//!
+//! Unlike [`RollbackMap::pin`](crate::RollbackMap::pin), reading an older state
+//! needs no cooperation of the writer: a held snapshot owns its nodes, so a later
+//! rollback or prune never reclaims them, and there is nothing to pin.
+use crate::persistent::{PersistentRollbackMap, Snapshot};
+use core::borrow::Borrow;
+use core::ops::Deref;
+use std::sync::{Arc, RwLock};
+
+type Published<K, V> = Arc<RwLock<Option<Arc<Snapshot<K, V>>>>>;
+
+/// A [`PersistentRollbackMap`] that publishes its checkpoints to reader threads.
+///
+/// Read access to the writer's own, uncommitted state is available through `Deref`.
+///
+/// # Examples
+///
+/// Basic usage:
+///
+/// ```
+/// use rollbackmap::sync::SyncRollbackMap;
+/// use std::thread;
+///
+/// let mut map = SyncRollbackMap::new();
+/// let reader = map.reader();
+/// map.insert(1, "a");
+/// map.checkpoint();
+/// map.insert(1, "b");
+///
+/// let committed = thread::spawn(move || reader.snapshot().unwrap().get(&1).copied())
+///     .join()
+///     .unwrap();
+/// assert_eq!(committed, Some("a"));
+/// assert_eq!(map.get(&1), Some(&"b"));
+/// ```
+#[derive(Debug)]
+pub struct SyncRollbackMap<K, V> {
+    map: PersistentRollbackMap<K, V>,
+    published: Published<K, V>,
+}
+
+/// A handle to read the snapshots published by a [`SyncRollbackMap`].
+///
+/// Handles are cheap to clone and can be sent to other threads.
+#[derive(Debug)]
+pub struct SyncReader<K, V> {
+    published: Published<K, V>,
+}
+
+impl<K, V> Clone for SyncReader<K, V> {
+    fn clone(&self) -> Self {
+        SyncReader {
+            published: Arc::clone(&self.published),
+        }
+    }
+}
+
+impl<K, V> SyncReader<K, V> {
+    /// Returns the last published snapshot, `None` until the first checkpoint.
+    ///
+    /// The returned snapshot is not affected by later checkpoints or rollbacks,
+    /// call this method again to see them.
+    pub fn snapshot(&self) -> Option<Arc<Snapshot<K, V>>> {
+        match self.published.read() {
+            Ok(published) => published.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+}
+
+impl<K, V> Default for SyncRollbackMap<K, V> {
+    /// Creates an empty `SyncRollbackMap`.
+    fn default() -> Self {
+        SyncRollbackMap::new()
+    }
+}
+
+impl<K, V> SyncRollbackMap<K, V> {
+    /// Makes a new, empty `SyncRollbackMap` with nothing published.
+    pub fn new() -> Self {
+        SyncRollbackMap {
+            map: PersistentRollbackMap::new(),
+            published: Arc::new(RwLock::new(None)),
+        }
+    }
+
+    /// Returns a new handle to read the published snapshots.
+    pub fn reader(&self) -> SyncReader<K, V> {
+        SyncReader {
+            published: Arc::clone(&self.published),
+        }
+    }
+
+    /// Clears the map.
+    /// See [`PersistentRollbackMap::clear`].
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Creates a checkpoint and publishes its snapshot to the readers.
+    /// See [`PersistentRollbackMap::checkpoint`].
+    pub fn checkpoint(&mut self) -> Option<u32> {
+        let checkpoint = self.map.checkpoint()?;
+        self.publish(checkpoint);
+        Some(checkpoint)
+    }
+
+    /// Rollbacks to saved checkpoint and publishes its snapshot to the readers.
+    /// See [`PersistentRollbackMap::rollback`].
+    ///
+    /// The snapshots of the discarded checkpoints that are already held
+    /// by the readers stay valid.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::sync::SyncRollbackMap;
+    ///
+    /// let mut map = SyncRollbackMap::new();
+    /// let reader = map.reader();
+    /// map.insert(1, "a");
+    /// let first_checkpoint = map.checkpoint().unwrap();
+    /// map.insert(1, "b");
+    /// map.checkpoint();
+    ///
+    /// let held = reader.snapshot().unwrap();
+    /// assert!(map.rollback(first_checkpoint));
+    /// assert_eq!(held.get(&1), Some(&"b"));
+    /// assert_eq!(reader.snapshot().unwrap().get(&1), Some(&"a"));
+    /// ```
+    pub fn rollback(&mut self, checkpoint: u32) -> bool {
+        let rollback = self.map.rollback(checkpoint);
+        if rollback {
+            self.publish(checkpoint);
+        }
+        rollback
+    }
+
+    /// Deletes all the checkpoints except the last one.
+    /// See [`PersistentRollbackMap::prune`].
+    ///
+    /// The published snapshot is kept, and the snapshots held by the readers
+    /// stay readable, whichever checkpoints they were taken at.
+    pub fn prune(&mut self) -> Option<u32> {
+        self.map.prune()
+    }
+
+    /// Returns the map, the readers keep the last published snapshot.
+    pub fn into_inner(self) -> PersistentRollbackMap<K, V> {
+        self.map
+    }
+
+    fn publish(&self, checkpoint: u32) {
+        let snapshot = self.map.snapshot(checkpoint).map(Arc::new);
+        match self.published.write() {
+            Ok(mut published) => *published = snapshot,
+            Err(poisoned) => *poisoned.into_inner() = snapshot,
+        }
+    }
+}
+
+impl<K: Ord + Clone, V: Clone> SyncRollbackMap<K, V> {
+    /// Inserts a key-value pair into the map.
+    /// See [`PersistentRollbackMap::insert`].
+    ///
+    /// The readers see the insertion after the next checkpoint.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        self.map.insert(key, value)
+    }
+
+    /// Removes a key from the map.
+    /// See [`PersistentRollbackMap::remove`].
+    ///
+    /// The readers see the removal after the next checkpoint.
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Ord + ?Sized,
+    {
+        self.map.remove(key)
+    }
+}
+
+impl<K, V> Deref for SyncRollbackMap<K, V> {
+    type Target = PersistentRollbackMap<K, V>;
+
+    fn deref(&self) -> &PersistentRollbackMap<K, V> {
+        &self.map
+    }
+}
@@ -1,7 +1,468 @@
 #![cfg(test)]
+// The assertions of the original tests compare booleans explicitly
+#![allow(clippy::bool_assert_comparison)]
 
-use crate::rollbackmap::RollbackMap;
+use crate::batch::WriteBatch;
+use crate::bloom::BloomRollbackMap;
+use crate::codec::{Crc32, LengthPrefixed, LittleEndian};
+use crate::durable::DurableRollbackMap;
+use crate::events::Event;
+use crate::indexed::IndexedRollbackMap;
+use crate::journal::{self, JournaledRollbackMap};
+use crate::optimistic::{Conflict, OptimisticRollbackMap};
+use crate::persistent::PersistentRollbackMap;
+use crate::rollbackmap::{Change, Previous, RollbackMap, SharedRollbackMap};
+use crate::snapshot;
+use crate::spill::SpillingRollbackMap;
+use crate::sync::SyncRollbackMap;
+use crate::validate::Inconsistency;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+// Shared harness of the randomized tests: a seeded generator of operations, and a model of
+// the visible state and of the saved checkpoints checked against the map after every one
+
+/// A random operation, the values inserted are the number of the operation
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Checkpoint,
+    /// Rolls back to one of the saved checkpoints, picked by the random number
+    Rollback(u32),
+    Prune,
+    Clear,
+    Remove(u32),
+    Insert(u32, u32),
+    /// An operation of a single test: random number, key and operation number
+    Custom(u32, u32, u32),
+}
+
+/// Relative weights of the random operations
+#[derive(Clone, Copy)]
+struct Mix {
+    checkpoint: u32,
+    rollback: u32,
+    prune: u32,
+    clear: u32,
+    remove: u32,
+    insert: u32,
+    custom: u32,
+}
+
+/// Linear congruential generator, the same seed gives the same numbers
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        self.0
+    }
+}
+
+/// Endless seeded sequence of random operations on the keys below `keys`
+struct Ops {
+    rng: Rng,
+    keys: u32,
+    mix: Mix,
+    count: u32,
+}
+
+impl Ops {
+    fn new(seed: u32, keys: u32, mix: Mix) -> Self {
+        Ops {
+            rng: Rng(seed),
+            keys,
+            mix,
+            count: 0,
+        }
+    }
+}
+
+impl Iterator for Ops {
+    type Item = Op;
+
+    fn next(&mut self) -> Option<Op> {
+        let seed = self.rng.next();
+        let (key, n) = ((seed >> 16) % self.keys, self.count);
+        self.count += 1;
+        let mix = self.mix;
+        let weights = [
+            mix.checkpoint,
+            mix.rollback,
+            mix.prune,
+            mix.clear,
+            mix.remove,
+            mix.insert,
+            mix.custom,
+        ];
+        let mut roll = (seed >> 8) % weights.iter().sum::<u32>();
+        let kind = weights
+            .iter()
+            .position(|&weight| {
+                let picked = roll < weight;
+                roll = roll.saturating_sub(weight);
+                picked
+            })
+            .unwrap();
+        Some(match kind {
+            0 => Op::Checkpoint,
+            1 => Op::Rollback(seed >> 20),
+            2 => Op::Prune,
+            3 => Op::Clear,
+            4 => Op::Remove(key),
+            5 => Op::Insert(key, n),
+            _ => Op::Custom(seed, key, n),
+        })
+    }
+}
+
+/// The operations of the randomized tests on every kind of map
+trait TestMap {
+    fn checkpoint(&mut self) -> Option<u32>;
+    fn rollback(&mut self, checkpoint: u32) -> bool;
+    fn prune(&mut self) -> Option<u32>;
+    fn clear(&mut self);
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32>;
+    fn remove(&mut self, key: u32) -> Option<u32>;
+    fn get(&self, key: u32) -> Option<u32>;
+    fn contains_key(&self, key: u32) -> bool;
+    fn len(&self) -> usize;
+}
+
+impl TestMap for RollbackMap<u32, u32> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint)
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune()
+    }
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        self.insert(key, value)
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        self.remove(&key)
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        self.get(&key).copied()
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        self.contains_key(&key)
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl TestMap for SharedRollbackMap<u32, u32> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint)
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune()
+    }
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        self.insert(key, Arc::new(value)).map(|value| *value)
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        self.remove(&key).map(|value| *value)
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        self.get(&key).map(|value| **value)
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        self.contains_key(&key)
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl TestMap for PersistentRollbackMap<u32, u32> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint)
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune()
+    }
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        self.insert(key, value)
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        self.remove(&key)
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        self.get(&key).copied()
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        self.contains_key(&key)
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl TestMap for JournaledRollbackMap<u32, String, Vec<u8>, LittleEndian, LengthPrefixed> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint().unwrap()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint).unwrap()
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune().unwrap()
+    }
+    fn clear(&mut self) {
+        self.clear().unwrap()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        let previous = self.insert(key, value.to_string()).unwrap();
+        previous.map(|value| value.parse().unwrap())
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        let previous = self.remove(&key).unwrap();
+        previous.map(|value| value.parse().unwrap())
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        (**self).get(&key).map(|value| value.parse().unwrap())
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        (**self).contains_key(&key)
+    }
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+impl TestMap for SpillingRollbackMap<u32, u32, LittleEndian, LittleEndian> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint().unwrap()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint).unwrap()
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune().unwrap()
+    }
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        self.insert(key, value).unwrap()
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        self.remove(&key).unwrap()
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        self.get(&key).unwrap().map(|value| *value)
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        self.contains_key(&key).unwrap()
+    }
+    fn len(&self) -> usize {
+        self.len()
+    }
+}
+
+impl TestMap for BloomRollbackMap<u32, u32> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint)
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune()
+    }
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        self.insert(key, value)
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        self.remove(&key)
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        self.get(&key).copied()
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        self.contains_key(&key)
+    }
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+impl TestMap for IndexedRollbackMap<u32, u32> {
+    fn checkpoint(&mut self) -> Option<u32> {
+        self.checkpoint()
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        self.rollback(checkpoint)
+    }
+    fn prune(&mut self) -> Option<u32> {
+        self.prune()
+    }
+    fn clear(&mut self) {
+        self.clear()
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        self.insert(key, value)
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        self.remove(&key)
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        self.get(&key).copied()
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        self.contains_key(&key)
+    }
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+}
+
+/// Two maps given the same operations, which must return the same results
+impl<A: TestMap, B: TestMap> TestMap for (A, B) {
+    fn checkpoint(&mut self) -> Option<u32> {
+        let checkpoint = self.0.checkpoint();
+        assert_eq!(self.1.checkpoint(), checkpoint);
+        checkpoint
+    }
+    fn rollback(&mut self, checkpoint: u32) -> bool {
+        let rolled_back = self.0.rollback(checkpoint);
+        assert_eq!(self.1.rollback(checkpoint), rolled_back);
+        rolled_back
+    }
+    fn prune(&mut self) -> Option<u32> {
+        let last = self.0.prune();
+        assert_eq!(self.1.prune(), last);
+        last
+    }
+    fn clear(&mut self) {
+        self.0.clear();
+        self.1.clear();
+    }
+    fn insert(&mut self, key: u32, value: u32) -> Option<u32> {
+        let previous = self.0.insert(key, value);
+        assert_eq!(self.1.insert(key, value), previous);
+        previous
+    }
+    fn remove(&mut self, key: u32) -> Option<u32> {
+        let previous = self.0.remove(key);
+        assert_eq!(self.1.remove(key), previous);
+        previous
+    }
+    fn get(&self, key: u32) -> Option<u32> {
+        let value = self.0.get(key);
+        assert_eq!(self.1.get(key), value);
+        value
+    }
+    fn contains_key(&self, key: u32) -> bool {
+        let found = self.0.contains_key(key);
+        assert_eq!(self.1.contains_key(key), found);
+        found
+    }
+    fn len(&self) -> usize {
+        let len = self.0.len();
+        assert_eq!(self.1.len(), len);
+        len
+    }
+}
+
+/// The state a map must show under random operations, with the state saved at every
+/// checkpoint that can still be rolled back to
+#[derive(Default)]
+struct Model {
+    map: BTreeMap<u32, u32>,
+    saved: Vec<(u32, BTreeMap<u32, u32>)>,
+    /// Checkpoints may fail to roll back, when a memory budget expired them
+    expiring: bool,
+}
+
+impl Model {
+    /// Applies an operation other than [`Op::Custom`] to the map and to the model
+    fn apply<M: TestMap>(&mut self, map: &mut M, op: Op) {
+        match op {
+            Op::Checkpoint => self.checkpoint(map),
+            Op::Rollback(roll) => self.rollback(map, roll, M::rollback),
+            Op::Prune => self.prune(map),
+            Op::Clear => {
+                map.clear();
+                self.map.clear();
+            }
+            Op::Remove(key) => assert_eq!(map.remove(key), self.map.remove(&key)),
+            Op::Insert(key, value) => {
+                assert_eq!(map.insert(key, value), self.map.insert(key, value))
+            }
+            Op::Custom(..) => panic!("{:?} is not a model operation", op),
+        }
+    }
+
+    fn checkpoint<M: TestMap>(&mut self, map: &mut M) {
+        let checkpoint = map.checkpoint().unwrap();
+        self.saved.push((checkpoint, self.map.clone()));
+    }
+
+    /// Rolls back to a saved checkpoint picked by `roll`, with the given rollback
+    fn rollback<M, F>(&mut self, map: &mut M, roll: u32, rollback: F)
+    where
+        F: FnOnce(&mut M, u32) -> bool,
+    {
+        if self.saved.is_empty() {
+            return;
+        }
+        let index = roll as usize % self.saved.len();
+        if rollback(map, self.saved[index].0) {
+            self.saved.truncate(index + 1);
+            self.map = self.saved[index].1.clone();
+        } else {
+            assert!(self.expiring);
+            self.saved.remove(index);
+        }
+    }
+
+    /// Prunes the map, only the last checkpoint is kept
+    fn prune<M: TestMap>(&mut self, map: &mut M) {
+        let last = self.saved.pop();
+        assert_eq!(
+            map.prune(),
+            last.as_ref().map(|&(checkpoint, _)| checkpoint)
+        );
+        self.saved = last.into_iter().collect();
+    }
+
+    /// Checks the length of the map and its values for all the keys below `keys`
+    fn check<M: TestMap>(&self, map: &M, keys: u32) {
+        assert_eq!(map.len(), self.map.len());
+        for key in 0..keys {
+            assert_eq!(map.get(key), self.map.get(&key).copied());
+            assert_eq!(map.contains_key(key), self.map.contains_key(&key));
+        }
+    }
+}
 
 #[test]
 fn test_insert() {
@@ -403,6 +864,8 @@ fn test_rollback() {
         map.checkpoint();
         let non_valid_checkkpoint = map.get_last_checkpoint().unwrap() + 1;
         assert_ne!(true, map.rollback(non_valid_checkkpoint));
+        assert_eq!(map.get(&1), Some(&"0".to_owned()));
+        assert_eq!(map.get_checkpoints_count(), 1);
     }
 }
 
@@ -458,4 +921,1721 @@ fn test_prune() {
         assert_eq!(map.get(&1), Some(&"xa"));
         assert_eq!(map.get(&2), None);
     }
+    // Changes of the deleted checkpoints are folded into the last one
+    {
+        let mut map = RollbackMap::new();
+        map.insert(3, "c");
+        map.insert(4, "d");
+        map.checkpoint();
+        map.insert(1, "a");
+        map.remove(&4);
+        map.clear();
+        map.insert(5, "e");
+        map.checkpoint();
+        map.insert(6, "f");
+        let last_checkpoint = map.checkpoint();
+        map.insert(2, "b");
+        assert_eq!(map.prune(), last_checkpoint);
+        assert_eq!(map.len(), 3);
+        let entries: Vec<_> = map.iter().collect();
+        assert_eq!(entries, vec![(&2, &"b"), (&5, &"e"), (&6, &"f")]);
+        assert!(map.rollback(last_checkpoint.unwrap()));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get(&5), Some(&"e"));
+        assert_eq!(map.get(&3), None);
+    }
+    {
+        let mut map = RollbackMap::new();
+        map.insert(3, "c");
+        map.checkpoint();
+        map.insert(1, "a");
+        map.checkpoint();
+        map.insert(2, "b");
+        map.prune();
+        assert_eq!(map.get(&3), Some(&"c"));
+        assert_eq!(map.len(), 3);
+    }
+}
+
+#[test]
+fn test_pins() {
+    let mut map = RollbackMap::new();
+    map.insert(1, b'a');
+    map.insert(2, b'b');
+    map.insert(3, b'c');
+    let first_checkpoint = map.checkpoint().unwrap();
+    map.remove(&2);
+    map.insert(4, b'd');
+    let second_checkpoint = map.checkpoint().unwrap();
+    map.insert(1, b'x');
+    let third_checkpoint = map.checkpoint().unwrap();
+    map.remove(&3);
+    assert!(map.pin(third_checkpoint + 1).is_none());
+
+    // The pinned checkpoint is deleted, but its state is still readable
+    let pin = map.pin(second_checkpoint).unwrap();
+    assert_eq!(pin.checkpoint(), second_checkpoint);
+    assert_eq!(map.prune(), Some(third_checkpoint));
+    assert_eq!(map.get_checkpoints_count(), 1);
+    assert_eq!(map.get_prev_checkpoint(), None);
+    assert!(!map.rollback(first_checkpoint));
+    assert!(!map.rollback(second_checkpoint));
+    assert!(map.pin(second_checkpoint).is_none());
+    let at_pin: Vec<_> = (1..=4).map(|key| map.get_at(&pin, &key)).collect();
+    assert_eq!(at_pin, vec![Some(&b'a'), None, Some(&b'c'), Some(&b'd')]);
+    assert_eq!(map.len(), 2);
+
+    // Retired layers are kept by the snapshots
+    {
+        let mut bytes = Vec::new();
+        map.write_to(&mut bytes, &LittleEndian, &LittleEndian)
+            .unwrap();
+        let restored: RollbackMap<u32, u8> =
+            RollbackMap::read_from(bytes.as_slice(), &LittleEndian, &LittleEndian).unwrap();
+        assert_eq!(restored.get_checkpoints_count(), 1);
+        assert_eq!(restored.versions.len(), 3);
+    }
+
+    // The layer is folded by the next prune after the pin is released
+    assert!(map.rollback(third_checkpoint));
+    assert_eq!(map.get(&3), Some(&b'c'));
+    map.remove(&3);
+    drop(pin);
+    assert_eq!(map.prune(), Some(third_checkpoint));
+    assert_eq!(map.versions.len(), 2);
+    let entries: Vec<_> = map.iter().collect();
+    assert_eq!(entries, vec![(&1, &b'x'), (&4, &b'd')]);
+
+    // Pinned checkpoints cannot be rolled back over, even from a journal
+    let fourth_checkpoint = map.checkpoint().unwrap();
+    map.insert(5, b'e');
+    let pin = map.pin(fourth_checkpoint).unwrap();
+    let copy = map.clone();
+    assert!(!map.rollback(third_checkpoint));
+    assert_eq!(map.get_checkpoints_count(), 2);
+    assert!(copy.pin(fourth_checkpoint).is_some());
+    std::thread::spawn(move || drop(pin)).join().unwrap();
+    assert!(map.rollback(third_checkpoint));
+
+    let mut journaled = JournaledRollbackMap::new(Vec::new(), LittleEndian, LittleEndian).unwrap();
+    let checkpoint = journaled.checkpoint().unwrap().unwrap();
+    journaled.checkpoint().unwrap();
+    let pin = journaled.pin(checkpoint + 1).unwrap();
+    assert!(!journaled.rollback(checkpoint).unwrap());
+    drop(pin);
+    assert!(journaled.rollback(checkpoint).unwrap());
+    let (map, journal) = journaled.into_parts();
+    let replayed: RollbackMap<u32, u32> =
+        RollbackMap::replay(journal.as_slice(), &LittleEndian, &LittleEndian).unwrap();
+    assert!(replayed.history_eq(&map));
+}
+
+#[test]
+fn test_persistent_map() {
+    // Insert + remove against BTreeMap with checkpoints and rollbacks
+    {
+        let mut map: PersistentRollbackMap<u32, u32> = PersistentRollbackMap::new();
+        let mut model = Model::default();
+        let mix = Mix {
+            checkpoint: 3,
+            rollback: 1,
+            prune: 1,
+            clear: 1,
+            remove: 20,
+            insert: 40,
+            custom: 0,
+        };
+        for op in Ops::new(7, 128, mix).take(2000) {
+            model.apply(&mut map, op);
+            assert_eq!(map.len(), model.map.len());
+        }
+        assert!(map.iter().eq(model.map.iter()));
+        model.check(&map, 130);
+    }
+    // Clone keeps checkpoints and is independent of the original
+    {
+        let mut map: PersistentRollbackMap<u32, &str> = PersistentRollbackMap::new();
+        map.insert(1, "a");
+        let checkpoint = map.checkpoint().unwrap();
+        map.insert(1, "b");
+        let mut copy = map.clone();
+        copy.insert(2, "c");
+        assert!(!map.contains_key(&2));
+        assert!(copy.rollback(checkpoint));
+        assert_eq!(copy.get(&1), Some(&"a"));
+        assert_eq!(map.get(&1), Some(&"b"));
+    }
+    // Prune keeps the last checkpoint only
+    {
+        let mut map: PersistentRollbackMap<u32, &str> = PersistentRollbackMap::new();
+        map.insert(1, "a");
+        let first_checkpoint = map.checkpoint().unwrap();
+        map.clear();
+        let second_checkpoint = map.checkpoint().unwrap();
+        map.insert(2, "b");
+        assert_eq!(map.prune(), Some(second_checkpoint));
+        assert_eq!(map.get_checkpoints_count(), 1);
+        assert!(!map.rollback(first_checkpoint));
+        assert!(map.rollback(second_checkpoint));
+        assert!(map.is_empty());
+    }
+}
+
+#[test]
+fn test_clone() {
+    let mut map: RollbackMap<u32, String> = RollbackMap::new();
+    map.insert(1, "a".to_owned());
+    let checkpoint = map.checkpoint().unwrap();
+    map.insert(1, "b".to_owned());
+    map.insert(2, "c".to_owned());
+
+    let mut copy = map.clone();
+    assert!(copy.history_eq(&map));
+    assert!(copy.rollback(checkpoint));
+    assert_eq!(copy.get(&1), Some(&"a".to_owned()));
+    assert!(!copy.contains_key(&2));
+    assert_eq!(map.get(&1), Some(&"b".to_owned()));
+    assert_eq!(map.get(&2), Some(&"c".to_owned()));
+}
+
+#[test]
+fn test_eq_hash() {
+    fn hash_of(map: &RollbackMap<u32, &str>) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        map.hash(&mut hasher);
+        hasher.finish()
+    }
+    // Same content built through different checkpoints
+    {
+        let mut a: RollbackMap<u32, &str> = RollbackMap::default();
+        a.insert(1, "a");
+        a.insert(2, "b");
+
+        let mut b: RollbackMap<u32, &str> = RollbackMap::new();
+        b.insert(2, "x");
+        b.insert(3, "c");
+        b.checkpoint();
+        b.insert(1, "a");
+        b.insert(2, "b");
+        b.remove(&3);
+
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+        assert!(!a.history_eq(&b));
+    }
+    // Cleared content differs from the one below the checkpoint
+    {
+        let mut a: RollbackMap<u32, &str> = RollbackMap::new();
+        a.insert(1, "a");
+        let b = a.clone();
+        a.checkpoint();
+        a.clear();
+        assert_ne!(a, b);
+        assert_eq!(a, RollbackMap::new());
+        a.rollback(a.get_last_checkpoint().unwrap());
+        assert_eq!(a, b);
+    }
+}
+
+#[test]
+fn test_collection_traits() {
+    // FromIterator + IntoIterator
+    {
+        let map: RollbackMap<u32, &str> = vec![(2, "b"), (1, "a"), (2, "c")].into_iter().collect();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map[&2], "c");
+        let entries: Vec<(u32, &str)> = map.into_iter().collect();
+        assert_eq!(entries, vec![(1, "a"), (2, "c")]);
+    }
+    // Extend inside a checkpoint is rolled back as a whole
+    {
+        let mut map: RollbackMap<u32, u32> = RollbackMap::new();
+        map.extend(vec![(1, 1), (2, 2)]);
+        let checkpoint = map.checkpoint().unwrap();
+        let source: BTreeMap<u32, u32> = (2..5).map(|n| (n, n * 10)).collect();
+        map.extend(&source);
+        assert_eq!(map.len(), 4);
+        let entries: Vec<(&u32, &u32)> = (&map).into_iter().collect();
+        assert_eq!(entries, vec![(&1, &1), (&2, &20), (&3, &30), (&4, &40)]);
+        assert!(map.rollback(checkpoint));
+        assert_eq!(map.len(), 2);
+        assert!(map.iter().eq(vec![(&1, &1), (&2, &2)]));
+    }
+    // Owned iteration skips entries hidden by clear and remove
+    {
+        let mut map: RollbackMap<u32, String> = RollbackMap::new();
+        map.insert(1, "a".to_owned());
+        map.checkpoint();
+        map.clear();
+        map.insert(2, "b".to_owned());
+        map.insert(3, "c".to_owned());
+        map.checkpoint();
+        map.remove(&3);
+        let entries: Vec<(u32, String)> = map.into_iter().rev().collect();
+        assert_eq!(entries, vec![(2, "b".to_owned())]);
+    }
+}
+
+#[test]
+#[should_panic(expected = "no entry found for key")]
+fn test_index_missing_key() {
+    let map: RollbackMap<u32, &str> = RollbackMap::new();
+    let _ = map[&1];
+}
+
+#[test]
+fn test_non_cloneable_values() {
+    #[derive(Debug, PartialEq)]
+    struct Handle(u32);
+
+    let mut map: RollbackMap<u32, Handle> = RollbackMap::new();
+    assert!(map.replace(1, Handle(1)).is_none());
+    assert!(map.replace(2, Handle(2)).is_none());
+    let checkpoint = map.checkpoint().unwrap();
+
+    assert_eq!(
+        map.replace(1, Handle(10)),
+        Some(Previous::Shadowed(&Handle(1)))
+    );
+    assert_eq!(
+        map.replace(1, Handle(11)),
+        Some(Previous::Owned(Handle(10)))
+    );
+    assert_eq!(map.take(&2).as_deref(), Some(&Handle(2)));
+    assert!(map.take(&2).is_none());
+    assert_eq!(map.len(), 1);
+    assert_eq!(map.get(&1), Some(&Handle(11)));
+
+    assert!(map.rollback(checkpoint));
+    assert_eq!(map.len(), 2);
+    assert_eq!(map[&1], Handle(1));
+    assert_eq!(map[&2], Handle(2));
+
+    // set and delete only report whether the key was present
+    assert!(map.set(1, Handle(12)));
+    assert!(!map.set(3, Handle(3)));
+    assert!(map.delete(&2));
+    assert!(!map.delete(&2));
+    assert!(map.delete(&3));
+    assert_eq!(map.len(), 1);
+    assert_eq!(map[&1], Handle(12));
+    assert!(map.rollback(checkpoint));
+    assert_eq!(map.len(), 2);
+    assert_eq!(map[&1], Handle(1));
+}
+
+#[test]
+fn test_shared_values() {
+    let mut map: SharedRollbackMap<u32, Vec<u32>> = SharedRollbackMap::new();
+    map.insert(1, Arc::new(vec![1]));
+    map.insert(2, Arc::new(vec![2]));
+    let checkpoint = map.checkpoint().unwrap();
+
+    // Untouched values are shared with the checkpoint
+    let replaced = map.insert(2, Arc::new(vec![20])).unwrap();
+    assert_eq!(Arc::strong_count(&replaced), 2);
+
+    // The first modification copies the value, the next ones are in place
+    map.make_mut(&1).unwrap().push(10);
+    let copied: *const Vec<u32> = &*map[&1];
+    map.make_mut(&1).unwrap().push(100);
+    assert!(std::ptr::eq(copied, &*map[&1]));
+    assert_eq!(*map[&1], vec![1, 10, 100]);
+    assert!(map.make_mut(&3).is_none());
+
+    assert!(map.rollback(checkpoint));
+    assert_eq!(*map[&1], vec![1]);
+    assert!(Arc::ptr_eq(&map[&2], &replaced));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde() {
+    // Round-trip keeps every version
+    {
+        let mut map: RollbackMap<u32, String> = RollbackMap::new();
+        map.insert(1, "a".to_owned());
+        map.insert(2, "b".to_owned());
+        let first_checkpoint = map.checkpoint().unwrap();
+        map.remove(&1);
+        map.insert(3, "c".to_owned());
+        let second_checkpoint = map.checkpoint().unwrap();
+        map.clear();
+        map.insert(4, "d".to_owned());
+
+        let json = serde_json::to_string(&map).unwrap();
+        let mut restored: RollbackMap<u32, String> = serde_json::from_str(&json).unwrap();
+        assert!(restored.history_eq(&map));
+        assert_eq!(restored.len(), 1);
+        assert!(restored.rollback(second_checkpoint));
+        assert_eq!(restored.len(), 2);
+        assert!(restored.rollback(first_checkpoint));
+        assert_eq!(restored.get(&1), Some(&"a".to_owned()));
+    }
+    // Malformed history is rejected
+    {
+        let empty = r#"{"versions":[]}"#;
+        assert!(serde_json::from_str::<RollbackMap<u32, u32>>(empty).is_err());
+        let version =
+            r#"{"removed_keys":[],"data":{},"detached":false,"checkpoint":1,"values_count":0}"#;
+        let unordered = format!(r#"{{"versions":[{0},{0}]}}"#, version);
+        assert!(serde_json::from_str::<RollbackMap<u32, u32>>(&unordered).is_err());
+        let retired = r#"{"versions":[{"removed_keys":[],"data":{},"detached":false,"checkpoint":1,"values_count":0,"retired":true}]}"#;
+        assert!(serde_json::from_str::<RollbackMap<u32, u32>>(retired).is_err());
+
+        // The layers have to be consistent as well
+        let miscounted = r#"{"versions":[{"removed_keys":[],"data":{"1":10},"detached":false,"checkpoint":0,"values_count":0}]}"#;
+        let error = serde_json::from_str::<RollbackMap<u32, u32>>(miscounted).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("tracks length 0, but has 1 values"));
+        let dangling = r#"{"versions":[{"removed_keys":[],"data":{"1":10},"detached":false,"checkpoint":0,"values_count":1},{"removed_keys":[2],"data":{},"detached":false,"checkpoint":1,"values_count":0}]}"#;
+        assert!(serde_json::from_str::<RollbackMap<u32, u32>>(dangling).is_err());
+    }
+}
+
+#[test]
+fn test_snapshot() {
+    let mut map: RollbackMap<String, u64> = RollbackMap::new();
+    for n in 0..10u64 {
+        map.insert(n.to_string(), n);
+    }
+    let first_checkpoint = map.checkpoint().unwrap();
+    map.remove("3");
+    map.insert("1".to_owned(), 100);
+    map.checkpoint();
+    map.clear();
+    map.insert("x".to_owned(), 0);
+
+    let mut bytes = Vec::new();
+    map.write_to(&mut bytes, &LengthPrefixed, &LittleEndian)
+        .unwrap();
+    assert_eq!(&bytes[..4], &snapshot::MAGIC);
+
+    // Round-trip keeps every version
+    {
+        let mut restored: RollbackMap<String, u64> =
+            RollbackMap::read_from(bytes.as_slice(), &LengthPrefixed, &LittleEndian).unwrap();
+        assert!(restored.history_eq(&map));
+        assert!(restored.rollback(first_checkpoint));
+        assert_eq!(restored.len(), 10);
+    }
+    // Any corrupted or missing byte is detected
+    for index in 0..bytes.len() {
+        let mut corrupted = bytes.clone();
+        corrupted[index] ^= 0x10;
+        let result: std::io::Result<RollbackMap<String, u64>> =
+            RollbackMap::read_from(corrupted.as_slice(), &LengthPrefixed, &LittleEndian);
+        assert!(result.is_err());
+        let result: std::io::Result<RollbackMap<String, u64>> =
+            RollbackMap::read_from(&bytes[..index], &LengthPrefixed, &LittleEndian);
+        assert!(result.is_err());
+    }
+    // Newer format versions are rejected
+    {
+        let mut newer = bytes.clone();
+        newer[4] = snapshot::FORMAT_VERSION as u8 + 1;
+        let error =
+            RollbackMap::<String, u64>::read_from(newer.as_slice(), &LengthPrefixed, &LittleEndian)
+                .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+    // Inconsistent layers are rejected
+    {
+        let mut miscounted = map.clone();
+        miscounted.versions.last_mut().unwrap().values_count += 1;
+        let mut bytes = Vec::new();
+        miscounted
+            .write_to(&mut bytes, &LengthPrefixed, &LittleEndian)
+            .unwrap();
+        let error =
+            RollbackMap::<String, u64>::read_from(bytes.as_slice(), &LengthPrefixed, &LittleEndian)
+                .unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+    // Format version 1 has no flag of the pruned checkpoints
+    {
+        let pin = map.pin(first_checkpoint).unwrap();
+        map.checkpoint();
+        map.prune();
+        drop(pin);
+        let mut bytes = Vec::new();
+        map.write_to(&mut bytes, &LengthPrefixed, &LittleEndian)
+            .unwrap();
+        bytes[4] = 1;
+        let body = bytes.len() - 4;
+        let mut crc = Crc32::new();
+        crc.update(&bytes[..body]);
+        bytes[body..].copy_from_slice(&crc.finish().to_le_bytes());
+        let error =
+            RollbackMap::<String, u64>::read_from(bytes.as_slice(), &LengthPrefixed, &LittleEndian)
+                .unwrap_err();
+        assert_eq!(error.to_string(), "unknown version flags");
+    }
+}
+
+#[test]
+fn test_journal() {
+    let mut map = JournaledRollbackMap::new(Vec::new(), LittleEndian, LengthPrefixed).unwrap();
+    let mut model = Model::default();
+    let mix = Mix {
+        checkpoint: 1,
+        rollback: 1,
+        prune: 1,
+        clear: 1,
+        remove: 6,
+        insert: 13,
+        custom: 0,
+    };
+    for op in Ops::new(11, 32, mix).take(500) {
+        model.apply(&mut map, op);
+        model.check(&map, 34);
+    }
+    // Unknown checkpoints and missing keys are not journaled
+    let journal_len = map.writer().len();
+    assert!(!map.rollback(u32::MAX).unwrap());
+    assert_eq!(map.remove(&1000).unwrap(), None);
+    assert_eq!(map.writer().len(), journal_len);
+
+    let (map, journal) = map.into_parts();
+    let replayed: RollbackMap<u32, String> =
+        RollbackMap::replay(journal.as_slice(), &LittleEndian, &LengthPrefixed).unwrap();
+    assert!(replayed.history_eq(&map));
+
+    // The layers kept by pins are kept by the replay
+    let mut map = JournaledRollbackMap::new(Vec::new(), LittleEndian, LengthPrefixed).unwrap();
+    map.insert(1, "a".to_string()).unwrap();
+    let first_checkpoint = map.checkpoint().unwrap().unwrap();
+    let pin = map.pin(first_checkpoint).unwrap();
+    map.insert(1, "b".to_string()).unwrap();
+    map.checkpoint().unwrap();
+    map.insert(2, "c".to_string()).unwrap();
+    map.prune().unwrap();
+    let (pinned, pinned_journal) = map.into_parts();
+    let replayed: RollbackMap<u32, String> =
+        RollbackMap::replay(pinned_journal.as_slice(), &LittleEndian, &LengthPrefixed).unwrap();
+    assert!(replayed.history_eq(&pinned));
+    drop(pin);
+
+    // A failed write poisons the journal, nothing is written after the partial record
+    struct FailingWriter {
+        written: Vec<u8>,
+        limit: usize,
+    }
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let len = buf.len().min(self.limit.saturating_sub(self.written.len()));
+            if len == 0 {
+                return Err(std::io::Error::other("journal is full"));
+            }
+            self.written.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    let writer = FailingWriter {
+        written: Vec::new(),
+        limit: usize::MAX,
+    };
+    let mut map = JournaledRollbackMap::new(writer, LittleEndian, LengthPrefixed).unwrap();
+    map.insert(1, "a".to_owned()).unwrap();
+    map.checkpoint().unwrap();
+    let committed = map.writer().written.len();
+    map.writer_mut().limit = committed + 3;
+    assert_eq!(
+        map.insert(2, "b".to_owned()).unwrap_err().kind(),
+        std::io::ErrorKind::Other
+    );
+    assert!(map.is_poisoned());
+    map.writer_mut().limit = usize::MAX;
+    assert_eq!(
+        map.insert(3, "c".to_owned()).unwrap_err().kind(),
+        std::io::ErrorKind::Other
+    );
+    assert!(map.checkpoint().is_err());
+    assert!(map.flush().is_err());
+    let (poisoned, writer) = map.into_parts();
+    assert_eq!(poisoned.len(), 1);
+    assert_eq!(writer.written.len(), committed + 3);
+    let replayed: RollbackMap<u32, String> =
+        RollbackMap::replay(&writer.written[..committed], &LittleEndian, &LengthPrefixed).unwrap();
+    assert!(replayed.history_eq(&poisoned));
+
+    // Truncated journal
+    let truncated: std::io::Result<RollbackMap<u32, String>> = RollbackMap::replay(
+        &journal[..journal.len() - 1],
+        &LittleEndian,
+        &LengthPrefixed,
+    );
+    assert_eq!(
+        truncated.unwrap_err().kind(),
+        std::io::ErrorKind::UnexpectedEof
+    );
+
+    // Corrupted journal
+    let mut corrupted = journal.clone();
+    let last = corrupted.len() - 5;
+    corrupted[last] ^= 1;
+    let corrupted: std::io::Result<RollbackMap<u32, String>> =
+        RollbackMap::replay(corrupted.as_slice(), &LittleEndian, &LengthPrefixed);
+    assert_eq!(
+        corrupted.unwrap_err().kind(),
+        std::io::ErrorKind::InvalidData
+    );
+}
+
+#[test]
+fn test_durable() {
+    let dir = std::env::temp_dir().join(format!("rollbackmap-test-durable-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let open = || DurableRollbackMap::<u32, String, _, _>::open(&dir, LittleEndian, LengthPrefixed);
+
+    // Mutations after the last commit point are discarded
+    let first_checkpoint;
+    {
+        let mut map = open().unwrap();
+        map.insert(1, "a".to_owned()).unwrap();
+        first_checkpoint = map.checkpoint().unwrap().unwrap();
+        map.insert(2, "b".to_owned()).unwrap();
+        map.checkpoint().unwrap();
+        map.remove(&1).unwrap();
+        assert!(map.rollback(first_checkpoint).unwrap());
+        map.insert(3, "c".to_owned()).unwrap();
+    }
+    {
+        let map = open().unwrap();
+        assert_eq!(map.get(&1), Some(&"a".to_owned()));
+        assert!(!map.contains_key(&2));
+        assert!(!map.contains_key(&3));
+        assert_eq!(map.get_last_checkpoint(), Some(first_checkpoint));
+    }
+
+    // A torn record is discarded with everything after the last commit point
+    let journal_path = dir.join("journal-0.log");
+    {
+        let mut map = open().unwrap();
+        map.insert(4, "d".to_owned()).unwrap();
+        map.checkpoint().unwrap();
+        map.insert(5, "e".to_owned()).unwrap();
+    }
+    let journal_len = std::fs::metadata(&journal_path).unwrap().len();
+    {
+        let file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(&journal_path)
+            .unwrap();
+        file.set_len(journal_len - 3).unwrap();
+    }
+    let expected = {
+        let mut map = open().unwrap();
+        assert_eq!(map.get(&4), Some(&"d".to_owned()));
+        assert!(!map.contains_key(&5));
+        assert_eq!(map.get_checkpoints_count(), 2);
+        map.insert(6, "f".to_owned()).unwrap();
+        map.checkpoint().unwrap();
+        map.into_inner()
+    };
+    assert!(open().unwrap().history_eq(&expected));
+
+    // Compaction replaces the journal by a snapshot
+    {
+        let mut map = open().unwrap();
+        map.insert(7, "g".to_owned()).unwrap();
+        map.compact().unwrap();
+        map.insert(8, "h".to_owned()).unwrap();
+        map.prune().unwrap();
+    }
+    {
+        let map = open().unwrap();
+        assert_eq!(map.get(&7), Some(&"g".to_owned()));
+        assert_eq!(map.get(&8), Some(&"h".to_owned()));
+        assert_eq!(map.get_checkpoints_count(), 1);
+    }
+    let mut files: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    assert_eq!(files, vec!["journal-1.log", "snapshot-1.bin"]);
+
+    // A corrupted record followed by others fails the open and keeps the journal
+    let journal_path = dir.join("journal-1.log");
+    {
+        let mut map = open().unwrap();
+        map.insert(9, "i".to_owned()).unwrap();
+        map.checkpoint().unwrap();
+        map.insert(10, "j".to_owned()).unwrap();
+        map.checkpoint().unwrap();
+    }
+    let mut bytes = std::fs::read(&journal_path).unwrap();
+    let journal_len = bytes.len() as u64;
+    // The first payload byte, after the header and the record length
+    bytes[journal::MAGIC.len() + 2 + 4] ^= 1;
+    std::fs::write(&journal_path, &bytes).unwrap();
+    assert_eq!(open().unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    assert_eq!(std::fs::metadata(&journal_path).unwrap().len(), journal_len);
+
+    // A corrupted last record is discarded as a torn one
+    bytes[journal::MAGIC.len() + 2 + 4] ^= 1;
+    *bytes.last_mut().unwrap() ^= 1;
+    std::fs::write(&journal_path, &bytes).unwrap();
+    {
+        let map = open().unwrap();
+        assert_eq!(map.get(&9), Some(&"i".to_owned()));
+        assert!(!map.contains_key(&10));
+    }
+
+    // A failed write is cut off the journal, the later commits survive reopen
+    let expected = {
+        let mut map = open().unwrap();
+        map.journal.writer.fail_after = Some(5);
+        assert!(map.insert(11, "k".to_owned()).is_err());
+        map.journal.writer.fail_after = None;
+        map.insert(12, "l".to_owned()).unwrap();
+        map.checkpoint().unwrap();
+        (*map).clone()
+    };
+    {
+        let map = open().unwrap();
+        assert!(map.history_eq(&expected));
+        assert!(!map.contains_key(&11));
+        assert_eq!(map.get(&12), Some(&"l".to_owned()));
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_spill() {
+    let path = std::env::temp_dir().join(format!("rollbackmap-test-spill-{}", std::process::id()));
+    // The spilled map notifies the same events as the map kept in memory
+    fn subscribe<V>(
+        map: &mut RollbackMap<u32, V>,
+        received: &Arc<std::sync::Mutex<Vec<Event<u32>>>>,
+    ) {
+        let sink = Arc::clone(received);
+        map.subscribe(move |event: &Event<&u32>| sink.lock().unwrap().push(event.cloned()));
+    }
+    let (received, expected_received) = (Arc::default(), Arc::default());
+    let mut observed = RollbackMap::new();
+    subscribe(&mut observed, &received);
+    let map =
+        SpillingRollbackMap::from_map(observed, &path, 3, LittleEndian, LittleEndian).unwrap();
+    let mut expected: RollbackMap<u32, u32> = RollbackMap::new();
+    subscribe(&mut expected, &expected_received);
+    let mut maps = (map, expected);
+    let mut model = Model::default();
+    let mix = Mix {
+        checkpoint: 25,
+        rollback: 5,
+        prune: 1,
+        clear: 5,
+        remove: 35,
+        insert: 70,
+        custom: 0,
+    };
+    for op in Ops::new(5, 32, mix).take(600) {
+        model.apply(&mut maps, op);
+        model.check(&maps, 32);
+
+        let (map, expected) = &maps;
+        assert_eq!(
+            std::mem::take(&mut *received.lock().unwrap()),
+            std::mem::take(&mut *expected_received.lock().unwrap())
+        );
+
+        // Only the hot window is kept in memory
+        assert!(map.hot_checkpoints_count() <= 2);
+        assert_eq!(
+            map.get_checkpoints_count(),
+            expected.get_checkpoints_count()
+        );
+        assert_eq!(map.get_prev_checkpoint(), expected.get_prev_checkpoint());
+    }
+    let (map, expected) = maps;
+    assert!(map.spilled_checkpoints_count() > 0);
+    assert!(map.into_map().unwrap().history_eq(&expected));
+    assert!(!path.exists());
+
+    // Every key in its own spilled version, looked up from several threads
+    let mut map = SpillingRollbackMap::new(&path, 2, LittleEndian, LittleEndian).unwrap();
+    for key in 0..64u32 {
+        map.insert(key, u64::from(key) * 10).unwrap();
+        map.checkpoint().unwrap();
+    }
+    assert_eq!(map.spilled_checkpoints_count(), 63);
+    let map = Arc::new(map);
+    let readers: Vec<_> = (0..8u32)
+        .map(|reader| {
+            let map = Arc::clone(&map);
+            std::thread::spawn(move || {
+                for n in 0..300 {
+                    let key = (reader * 7 + n) % 80;
+                    let expected = if key < 64 {
+                        Some(u64::from(key) * 10)
+                    } else {
+                        None
+                    };
+                    assert_eq!(map.get(&key).unwrap().as_deref(), expected.as_ref());
+                }
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    drop(map);
+
+    // A pinned checkpoint cannot be rolled back over, even to a spilled one
+    let mut pinned = RollbackMap::new();
+    pinned.insert(1, 1u64);
+    let first_checkpoint = pinned.checkpoint().unwrap();
+    pinned.insert(1, 2);
+    let second_checkpoint = pinned.checkpoint().unwrap();
+    pinned.insert(1, 3);
+    pinned.checkpoint().unwrap();
+    let pin = pinned.pin(second_checkpoint).unwrap();
+    let mut map =
+        SpillingRollbackMap::from_map(pinned, &path, 2, LittleEndian, LittleEndian).unwrap();
+    assert_eq!(map.spilled_checkpoints_count(), 2);
+    assert!(!map.rollback(first_checkpoint).unwrap());
+    assert_eq!(map.get(&1).unwrap().as_deref(), Some(&3));
+    drop(pin);
+    assert!(map.rollback(first_checkpoint).unwrap());
+    assert_eq!(map.get(&1).unwrap().as_deref(), Some(&1));
+}
+
+#[test]
+fn test_sync_map() {
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+
+    let mut map: SyncRollbackMap<u32, u32> = SyncRollbackMap::new();
+    let reader = map.reader();
+    assert_send_sync(&reader);
+    assert!(reader.snapshot().is_none());
+
+    // Every committed state has the same value for all the keys
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let reader = reader.clone();
+            std::thread::spawn(move || {
+                let mut seen = 0;
+                while seen < 1000 {
+                    if let Some(snapshot) = reader.snapshot() {
+                        assert_send_sync(&*snapshot);
+                        assert_eq!(snapshot.len(), 8);
+                        let value = snapshot.get(&0).copied();
+                        assert!(snapshot.iter().all(|(_, v)| Some(*v) == value));
+                        seen += 1;
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let mut checkpoints = Vec::new();
+    for n in 0..200 {
+        for key in 0..8 {
+            map.insert(key, n);
+            // uncommitted changes are not visible
+            map.remove(&(key + 100));
+        }
+        if n % 7 == 6 {
+            map.insert(100, n);
+            let checkpoint = checkpoints[checkpoints.len() / 2];
+            checkpoints.truncate(checkpoints.len() / 2 + 1);
+            let held = reader.snapshot().unwrap();
+            assert!(map.rollback(checkpoint));
+            assert_eq!(held.get(&0), Some(&(n - 1)));
+            assert_eq!(reader.snapshot().unwrap().checkpoint(), checkpoint);
+        } else {
+            checkpoints.extend(map.checkpoint());
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    // A snapshot of a pruned checkpoint stays readable without pinning it
+    let oldest = checkpoints[0];
+    assert!(map.rollback(oldest));
+    let held = reader.snapshot().unwrap();
+    map.insert(0, 1000);
+    map.checkpoint();
+    let last = reader.snapshot().unwrap();
+    assert_eq!(map.prune(), Some(last.checkpoint()));
+    assert!(!map.rollback(oldest));
+    assert_eq!(held.checkpoint(), oldest);
+    assert_eq!(
+        std::thread::spawn(move || held.get(&0).copied())
+            .join()
+            .unwrap(),
+        Some(0)
+    );
+    map.clear();
+    assert!(map.is_empty());
+    assert_eq!(reader.snapshot().unwrap().len(), 8);
+}
+
+#[test]
+fn test_optimistic_transactions() {
+    let map = OptimisticRollbackMap::new(RollbackMap::new());
+
+    // Blind writes do not conflict, reads of the written keys do
+    {
+        let mut first = map.begin();
+        let mut second = map.begin();
+        let mut third = map.begin();
+        first.insert(1, 10);
+        second.insert(1, 20);
+        assert_eq!(third.get(&1), None);
+        third.insert(2, 30);
+        assert_eq!(first.commit(), Ok(()));
+        assert_eq!(second.commit(), Ok(()));
+        assert_eq!(third.commit(), Err(Conflict));
+        assert_eq!(map.get(&1), Some(20));
+        assert_eq!(map.get(&2), None);
+    }
+    // Own writes are read back, transactions that began later do not conflict
+    {
+        let mut first = map.begin();
+        first.remove(&1);
+        assert_eq!(first.get(&1), None);
+        first.commit().unwrap();
+        let mut second = map.begin();
+        assert_eq!(second.get(&1), None);
+        second.insert(1, 1);
+        assert_eq!(second.commit(), Ok(()));
+    }
+    // Dropped transactions are discarded, a rollback conflicts with everything
+    {
+        let checkpoint = map.checkpoint().unwrap();
+        let mut dropped = map.begin();
+        dropped.insert(3, 3);
+        drop(dropped);
+        assert_eq!(map.get(&3), None);
+
+        let mut first = map.begin();
+        assert_eq!(first.get(&5), None);
+        let mut second = map.begin();
+        second.insert(4, 4);
+        second.commit().unwrap();
+        assert!(map.rollback(checkpoint));
+        assert_eq!(first.commit(), Err(Conflict));
+        assert_eq!(map.read(|map| map.len()), 1);
+    }
+
+    // Concurrent increments are serialized by retries
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                let mut conflicts = 0;
+                for n in 0..100 {
+                    loop {
+                        let mut transaction = map.begin();
+                        let total = transaction.get(&0).unwrap_or(0);
+                        transaction.insert(0, total + 1);
+                        transaction.insert(n + 100, n);
+                        match transaction.commit() {
+                            Ok(()) => break,
+                            Err(Conflict) => conflicts += 1,
+                        }
+                    }
+                }
+                conflicts
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    assert_eq!(map.get(&0), Some(400));
+    let map = map.try_into_inner().unwrap();
+    assert_eq!(map.len(), 102);
+}
+
+#[test]
+fn test_events() {
+    let received = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let mut map = RollbackMap::new();
+    let sink = Arc::clone(&received);
+    let subscription = map.subscribe(move |event: &Event<&u32>| {
+        sink.lock().unwrap().push(event.cloned());
+    });
+    let take_events = || std::mem::take(&mut *received.lock().unwrap());
+
+    map.insert(1, "a");
+    map.insert(1, "b");
+    map.insert(2, "c");
+    map.insert(3, "d");
+    let first_checkpoint = map.checkpoint().unwrap();
+    map.insert(2, "x");
+    map.remove(&3);
+    map.remove(&3);
+    map.insert(4, "e");
+    let second_checkpoint = map.checkpoint().unwrap();
+    map.remove(&4);
+    *map.get_mut(&1).unwrap() = "y";
+    assert_eq!(
+        take_events(),
+        vec![
+            Event::Inserted(1),
+            Event::Updated(1),
+            Event::Inserted(2),
+            Event::Inserted(3),
+            Event::CheckpointCreated(first_checkpoint),
+            Event::Updated(2),
+            Event::Removed(3),
+            Event::Inserted(4),
+            Event::CheckpointCreated(second_checkpoint),
+            Event::Removed(4),
+            Event::Updated(1),
+        ]
+    );
+
+    // Exactly the keys changed after the checkpoint are reverted
+    assert!(map.rollback(first_checkpoint));
+    assert_eq!(
+        take_events(),
+        vec![Event::RolledBack {
+            to: first_checkpoint,
+            reverted_keys: vec![1, 2, 3, 4],
+        }]
+    );
+
+    // A clear reverts all the keys of the checkpoint
+    map.clear();
+    map.insert(5, "f");
+    assert!(map.rollback(first_checkpoint));
+    assert_eq!(
+        take_events(),
+        vec![
+            Event::Cleared,
+            Event::Inserted(5),
+            Event::RolledBack {
+                to: first_checkpoint,
+                reverted_keys: vec![1, 2, 3, 5],
+            },
+        ]
+    );
+
+    // Clones are not observed, unsubscribed observers are not called
+    let mut copy = map.clone();
+    copy.insert(6, "g");
+    assert!(map.unsubscribe(subscription));
+    assert!(!map.unsubscribe(subscription));
+    map.insert(7, "h");
+    assert!(take_events().is_empty());
+
+    // The observers are notified once the change is applied
+    let mut map = RollbackMap::new();
+    map.subscribe(|event: &Event<&u32>| {
+        if let Event::Inserted(_) | Event::Updated(_) | Event::Removed(_) = event {
+            panic!("observer failed");
+        }
+    });
+    let fails = |change: &mut dyn FnMut()| {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(change));
+        assert!(result.is_err());
+    };
+    fails(&mut || {
+        map.insert(1, "a");
+    });
+    assert_eq!(map.get(&1), Some(&"a"));
+    map.checkpoint();
+    fails(&mut || {
+        map.insert(1, "b");
+    });
+    assert_eq!(map.get(&1), Some(&"b"));
+    fails(&mut || {
+        map.remove(&1);
+    });
+    assert_eq!(map.get(&1), None);
+    fails(&mut || {
+        map.insert_many(vec![(1, "c"), (2, "d")]);
+    });
+    assert_eq!(map.len(), 2);
+    fails(&mut || {
+        map.remove_many(vec![1, 2]);
+    });
+    assert!(map.is_empty());
+    assert_eq!(map.validate(), Ok(()));
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn test_event_stream() {
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    let mut map = RollbackMap::new();
+    let dropped = map.stream();
+    drop(dropped);
+    map.insert(0, "x".to_owned());
+    assert!(map.observers.is_empty());
+
+    let events = map.stream();
+    map.insert(1, "a".to_owned());
+    let checkpoint = map.checkpoint().unwrap();
+    map.insert(2, "b".to_owned());
+    map.rollback(checkpoint);
+
+    let worker = std::thread::spawn(move || block_on(events.collect::<Vec<_>>()));
+    drop(map);
+    assert_eq!(
+        worker.join().unwrap(),
+        vec![
+            Event::Inserted(1),
+            Event::CheckpointCreated(checkpoint),
+            Event::Inserted(2),
+            Event::RolledBack {
+                to: checkpoint,
+                reverted_keys: vec![2],
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_rollback_with_changes() {
+    let mut map: RollbackMap<u32, u32> = RollbackMap::new();
+    let mut model = Model::default();
+    let mix = Mix {
+        checkpoint: 4,
+        rollback: 1,
+        prune: 0,
+        clear: 1,
+        remove: 7,
+        insert: 18,
+        custom: 0,
+    };
+    for op in Ops::new(3, 24, mix).take(3000) {
+        match op {
+            Op::Rollback(roll) => model.rollback(&mut map, roll, |map, checkpoint| {
+                let before: BTreeMap<u32, u32> = map.iter().map(|(k, v)| (*k, *v)).collect();
+                let changes = map.rollback_with_changes(checkpoint).unwrap();
+                let after: BTreeMap<u32, u32> = map.iter().map(|(k, v)| (*k, *v)).collect();
+
+                // Every change is reported with the actual values
+                for change in &changes {
+                    assert_eq!(change.before.as_ref(), before.get(&change.key));
+                    assert_eq!(change.after.as_ref(), after.get(&change.key));
+                }
+                let reported: Vec<u32> = changes.iter().map(|change| change.key).collect();
+                for key in 0..24 {
+                    if before.get(&key) != after.get(&key) {
+                        assert!(reported.contains(&key));
+                    }
+                }
+                true
+            }),
+            op => model.apply(&mut map, op),
+        }
+        model.check(&map, 24);
+    }
+
+    assert_eq!(map.rollback_with_changes(u32::MAX), None);
+    let checkpoint = map.checkpoint().unwrap();
+    assert_eq!(map.rollback_with_changes(checkpoint), Some(Vec::new()));
+    map.insert(100, 1);
+    let changes = map.rollback_with_changes(checkpoint).unwrap();
+    assert_eq!(
+        changes,
+        vec![Change {
+            key: 100,
+            before: Some(1),
+            after: None,
+        }]
+    );
+}
+
+#[test]
+fn test_stats() {
+    let mut map: RollbackMap<u32, String> = RollbackMap::new();
+    let stats = map.stats();
+    assert_eq!(stats.layers.len(), 1);
+    assert_eq!((stats.len, stats.shadowed, stats.entries()), (0, 0, 0));
+
+    for key in 0..10 {
+        map.insert(key, key.to_string());
+    }
+    let first_checkpoint = map.checkpoint().unwrap();
+    map.insert(0, "zero".to_owned());
+    map.remove(&1);
+    map.insert(10, "ten".to_owned());
+    map.checkpoint();
+    map.clear();
+    map.insert(2, "two".to_owned());
+
+    let stats = map.stats();
+    let layers: Vec<(u32, usize, usize, usize, bool)> = stats
+        .layers
+        .iter()
+        .map(|layer| {
+            (
+                layer.checkpoint,
+                layer.entries,
+                layer.tombstones,
+                layer.shadowed,
+                layer.detached,
+            )
+        })
+        .collect();
+    assert_eq!(
+        layers,
+        vec![
+            (first_checkpoint, 10, 0, 10, false),
+            (first_checkpoint + 1, 2, 1, 2, false),
+            (first_checkpoint + 2, 1, 0, 0, true),
+        ]
+    );
+    assert_eq!(stats.len, map.len());
+    assert_eq!(stats.shadowed, 12);
+    assert_eq!(stats.entries(), 13);
+    assert_eq!(stats.tombstones(), 1);
+
+    // The hook adds the memory owned by the keys and values
+    let owned = map.stats_with(|_| 1, |value| value.len());
+    assert_eq!(owned.heap_size, stats.heap_size + 14 + 10 + 4 + 3 + 3);
+
+    // Retired layers are reported until the pin is dropped
+    let pin = map.pin(first_checkpoint).unwrap();
+    map.checkpoint();
+    map.prune();
+    let stats = map.stats();
+    assert!(stats.layers[0].retired);
+    assert_eq!(stats.layers[0].checkpoint, first_checkpoint);
+    drop(pin);
+    map.prune();
+    let stats = map.stats();
+    assert!(stats.layers.iter().all(|layer| !layer.retired));
+    assert_eq!(stats.shadowed, 0);
+    assert_eq!(stats.len, 1);
+}
+
+#[test]
+fn test_memory_budget() {
+    // Expiring checkpoints keeps the visible state and the newer checkpoints
+    {
+        let mut map: RollbackMap<u32, u32> = RollbackMap::new();
+        map.set_memory_budget(600, |_| 0, |_| 0);
+        let expired = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = Arc::clone(&expired);
+        map.subscribe(move |event: &Event<&u32>| {
+            if let Event::CheckpointsExpired(checkpoints) = event {
+                sink.lock().unwrap().extend(checkpoints);
+            }
+        });
+
+        let mut model = Model {
+            expiring: true,
+            ..Model::default()
+        };
+        let mix = Mix {
+            checkpoint: 3,
+            rollback: 1,
+            prune: 0,
+            clear: 0,
+            remove: 6,
+            insert: 19,
+            custom: 0,
+        };
+        for op in Ops::new(11, 64, mix).take(2000) {
+            model.apply(&mut map, op);
+            model.check(&map, 64);
+            assert!(map.history_size().unwrap() <= 600 || map.get_checkpoints_count() <= 1);
+
+            // The tracked size matches a fresh estimate
+            let mut fresh = map.clone();
+            fresh.set_memory_budget(usize::MAX, |_| 0, |_| 0);
+            assert_eq!(fresh.history_size(), map.history_size());
+        }
+
+        // The remaining checkpoints are the newest ones
+        let expired = expired.lock().unwrap();
+        assert!(!expired.is_empty());
+        for checkpoint in expired.iter() {
+            assert_eq!(map.pin(*checkpoint).map(|pin| pin.checkpoint()), None);
+        }
+        let (last, state) = model.saved.last().unwrap();
+        assert_eq!(map.get_last_checkpoint(), Some(*last));
+        assert!(map.rollback(*last));
+        assert!(map.iter().eq(state.iter()));
+    }
+    // Pinned checkpoints do not expire
+    {
+        let mut map: RollbackMap<u32, String> = RollbackMap::new();
+        map.insert(0, "base".to_owned());
+        let first_checkpoint = map.checkpoint().unwrap();
+        let pin = map.pin(first_checkpoint).unwrap();
+        for key in 1..4 {
+            map.insert(key, key.to_string());
+            map.checkpoint();
+        }
+        map.set_memory_budget(0, |_| 0, |value| value.capacity());
+        assert_eq!(map.get_checkpoints_count(), 2);
+        assert_eq!(map.get_prev_checkpoint(), Some(first_checkpoint));
+        assert_eq!(map.get_at(&pin, &1), None);
+        assert_eq!(map.memory_budget(), Some(0));
+
+        drop(pin);
+        let last_checkpoint = map.checkpoint().unwrap();
+        assert_eq!(map.get_checkpoints_count(), 1);
+        assert!(map.pin(first_checkpoint).is_none());
+        assert_eq!(map.get_last_checkpoint(), Some(last_checkpoint));
+        assert_eq!(map.history_size(), Some(0));
+        assert_eq!(map.len(), 4);
+
+        map.clear_memory_budget();
+        map.checkpoint();
+        assert_eq!(map.get_checkpoints_count(), 2);
+        assert_eq!(map.history_size(), None);
+    }
+}
+
+#[test]
+fn test_dump_layers() {
+    let mut map: RollbackMap<u32, &str> = RollbackMap::new();
+    map.insert(1, "a");
+    map.insert(2, "b");
+    let first_checkpoint = map.checkpoint().unwrap();
+    let pin = map.pin(first_checkpoint).unwrap();
+    map.insert(2, "c");
+    map.remove(&1);
+    map.checkpoint();
+    map.clear();
+    map.insert(2, "d");
+    map.checkpoint();
+    map.insert(3, "e");
+    map.prune();
+
+    let dump = map.dump_layers();
+    assert_eq!(dump.layers.len(), 3);
+    assert!(dump.layers[0].retired);
+    assert_eq!(dump.layers[1].added, vec![(&2, &"d")]);
+    assert!(dump.layers[2].current);
+    assert_eq!(
+        dump.to_string(),
+        "checkpoint 0 (retired)\n  + 1: \"a\"\n  + 2: \"b\"\n\
+         checkpoint 2 (detached)\n  + 2: \"d\"\n\
+         current, next checkpoint 3\n  + 3: \"e\"\n"
+    );
+    assert_eq!(
+        map.to_dot(),
+        "digraph rollbackmap {\n    rankdir=LR;\n    node [shape=box];\n    \
+         v0 [label=\"checkpoint 0 (retired)\\n+2 ~0 -0\", style=filled, fillcolor=lightgray];\n    \
+         v1 [label=\"checkpoint 2 (detached)\\n+1 ~0 -0\"];\n    \
+         v2 [label=\"current, next checkpoint 3\\n+1 ~0 -0\", style=bold];\n    \
+         v0 -> v1 [style=dashed, label=\"clear\"];\n    \
+         v1 -> v2;\n}\n"
+    );
+
+    // Updated and removed keys of the layers above the pin
+    drop(pin);
+    map.prune();
+    map.insert(2, "f");
+    map.remove(&3);
+    let dump = map.dump_layers();
+    assert_eq!(dump.layers.len(), 2);
+    assert_eq!(dump.layers[1].updated, vec![(&2, &"f")]);
+    assert_eq!(dump.layers[1].removed, Vec::<&u32>::new());
+    map.checkpoint();
+    map.remove(&2);
+    assert_eq!(map.dump_layers().layers[2].removed, vec![&2]);
+}
+
+#[test]
+fn test_bloom_filters() {
+    let mut map: BloomRollbackMap<u32, u32> = BloomRollbackMap::from_map(RollbackMap::new(), 4);
+    let mut model = Model::default();
+    let mix = Mix {
+        checkpoint: 4,
+        rollback: 1,
+        prune: 1,
+        clear: 1,
+        remove: 8,
+        insert: 26,
+        custom: 0,
+    };
+    for op in Ops::new(5, 200, mix).take(4000) {
+        model.apply(&mut map, op);
+        model.check(&map, 220);
+    }
+
+    // Layers folded by the memory budget get new filters
+    let mut inner = map.into_inner();
+    inner.set_memory_budget(0, |_| 0, |_| 0);
+    let mut map = BloomRollbackMap::from_map(inner, 8);
+    for key in 0..100 {
+        map.insert(key % 10, key);
+        map.checkpoint();
+        for key in 0..12 {
+            assert_eq!(map.get(&key), map.deref().get(&key));
+        }
+    }
+
+    // Borrowed forms of the keys hash the same
+    let mut map: BloomRollbackMap<String, u32> = BloomRollbackMap::new();
+    map.insert("a".to_owned(), 1);
+    map.checkpoint();
+    assert_eq!(map.get("a"), Some(&1));
+    assert!(!map.contains_key("b"));
+}
+
+#[test]
+fn test_indexed_map() {
+    let mut map: IndexedRollbackMap<u32, u32> = IndexedRollbackMap::new();
+    let mut model = Model::default();
+    let mix = Mix {
+        checkpoint: 4,
+        rollback: 2,
+        prune: 1,
+        clear: 1,
+        remove: 10,
+        insert: 25,
+        custom: 0,
+    };
+    for op in Ops::new(17, 100, mix).take(5000) {
+        model.apply(&mut map, op);
+        model.check(&map, 110);
+    }
+
+    // The index is rebuilt when the memory budget folds the layers
+    let mut inner = map.into_inner();
+    inner.set_memory_budget(0, |_| 0, |_| 0);
+    let mut map = IndexedRollbackMap::from_map(inner);
+    for key in 0..100 {
+        map.insert(key % 10, key);
+        map.checkpoint();
+        map.remove(&(key % 7));
+        for key in 0..12 {
+            assert_eq!(map.get(&key), map.deref().get(&key));
+        }
+    }
+
+    // Keys are looked up by their borrowed forms
+    let mut map: IndexedRollbackMap<String, u32> = IndexedRollbackMap::new();
+    map.insert("a".to_owned(), 1);
+    map.checkpoint();
+    assert_eq!(map.remove("a"), Some(1));
+    assert!(!map.contains_key("a"));
+}
+
+#[test]
+fn test_write_batch() {
+    let mut map: RollbackMap<u32, u32> = RollbackMap::new();
+    let mut reference: RollbackMap<u32, u32> = RollbackMap::new();
+    let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let reference_events = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let sink = Arc::clone(&events);
+    map.subscribe(move |event: &Event<&u32>| sink.lock().unwrap().push(event.cloned()));
+    let sink = Arc::clone(&reference_events);
+    reference.subscribe(move |event: &Event<&u32>| sink.lock().unwrap().push(event.cloned()));
+
+    let mut maps = (map, reference);
+    let mut model = Model::default();
+    let mix = Mix {
+        checkpoint: 2,
+        rollback: 1,
+        prune: 0,
+        clear: 1,
+        remove: 0,
+        insert: 0,
+        custom: 9,
+    };
+    for op in Ops::new(23, 64, mix).take(600) {
+        match op {
+            Op::Custom(roll, _, n) => {
+                // The last change of a key wins
+                let (map, reference) = &mut maps;
+                let mut batch = WriteBatch::new();
+                let mut changes = BTreeMap::new();
+                let mut rng = Rng(roll);
+                for change in 0..(roll >> 24) % 40 {
+                    let seed = rng.next();
+                    let key = (seed >> 16) % 64;
+                    match (seed >> 8) % 3 {
+                        0 => {
+                            batch.remove(key);
+                            changes.insert(key, None);
+                        }
+                        _ => {
+                            batch.insert(key, n * 100 + change);
+                            changes.insert(key, Some(n * 100 + change));
+                        }
+                    }
+                }
+                assert_eq!(batch.len(), changes.len());
+
+                let mut previous = Vec::new();
+                for (key, value) in changes {
+                    let returned = match value {
+                        Some(value) => reference.insert(key, value),
+                        None => reference.remove(&key),
+                    };
+                    let expected = match value {
+                        Some(value) => model.map.insert(key, value),
+                        None => model.map.remove(&key),
+                    };
+                    assert_eq!(returned, expected);
+                    previous.push((key, expected));
+                }
+                assert_eq!(map.write(batch), previous);
+            }
+            op => model.apply(&mut maps, op),
+        }
+        model.check(&maps, 64);
+    }
+    assert_eq!(*events.lock().unwrap(), *reference_events.lock().unwrap());
+
+    // insert_many and remove_many
+    let mut map: RollbackMap<u32, &str> = RollbackMap::new();
+    assert_eq!(
+        map.insert_many(vec![(1, "a"), (2, "b"), (1, "c")]),
+        vec![(1, None), (2, None)]
+    );
+    assert_eq!(map.get(&1), Some(&"c"));
+    let checkpoint = map.checkpoint().unwrap();
+    map.insert(2, "d");
+    assert_eq!(
+        map.remove_many(vec![1, 2, 3]),
+        vec![(1, Some("c")), (2, Some("d")), (3, None)]
+    );
+    assert!(map.is_empty());
+    assert_eq!(map.insert_many(Vec::new()), Vec::new());
+    assert!(map.rollback(checkpoint));
+    assert_eq!(map.len(), 2);
+    assert_eq!(map.get(&2), Some(&"b"));
+}
+
+#[test]
+fn test_len_consistency() {
+    // Every mutation keeps the length exact, checked against a merged model
+    fn check(map: &SharedRollbackMap<u32, u32>, model: &Model) {
+        map.debug_assert_consistency();
+        assert_eq!(map.validate(), Ok(()));
+        model.check(map, 24);
+        let visible: BTreeMap<u32, u32> = map.iter().map(|(&k, v)| (k, **v)).collect();
+        assert_eq!(visible, model.map);
+    }
+
+    for &limit in [None, Some(400)].iter() {
+        let mut map: SharedRollbackMap<u32, u32> = SharedRollbackMap::new();
+        if let Some(limit) = limit {
+            map.set_memory_budget(limit, |_| 0, |_| 0);
+        }
+        // Only the memory budget expires checkpoints
+        let mut model = Model {
+            expiring: limit.is_some(),
+            ..Model::default()
+        };
+        let mix = Mix {
+            checkpoint: 4,
+            rollback: 2,
+            prune: 1,
+            clear: 1,
+            remove: 3,
+            insert: 0,
+            custom: 29,
+        };
+        for op in Ops::new(17, 24, mix).take(3000) {
+            match op {
+                Op::Rollback(roll) if roll % 2 == 0 => {
+                    model.rollback(&mut map, roll, |map, checkpoint| {
+                        map.rollback_with_changes(checkpoint).is_some()
+                    })
+                }
+                Op::Prune => {
+                    let pin = model
+                        .saved
+                        .first()
+                        .and_then(|&(checkpoint, _)| map.pin(checkpoint));
+                    model.prune(&mut map);
+                    drop(pin);
+                }
+                Op::Custom(roll, key, n) => {
+                    let model = &mut model.map;
+                    match (roll >> 24) % 29 {
+                        0 | 1 => {
+                            let taken = map.take(&key).map(|value| *value.into_owned());
+                            assert_eq!(taken, model.remove(&key));
+                        }
+                        2 | 3 => assert_eq!(map.delete(&key), model.remove(&key).is_some()),
+                        4 => {
+                            if let Some(value) = map.get_mut(&key) {
+                                *Arc::make_mut(value) += 1;
+                            }
+                            if let Some(value) = model.get_mut(&key) {
+                                *value += 1;
+                            }
+                        }
+                        5 => {
+                            if let Some(value) = map.make_mut(&key) {
+                                *value += 2;
+                            }
+                            if let Some(value) = model.get_mut(&key) {
+                                *value += 2;
+                            }
+                        }
+                        6 | 7 => {
+                            let mut batch = WriteBatch::new();
+                            for offset in 0..4 {
+                                let key = (key + offset * 5) % 24;
+                                match (roll >> offset) % 3 {
+                                    0 => {
+                                        batch.remove(key);
+                                        model.remove(&key);
+                                    }
+                                    _ => {
+                                        batch.insert(key, Arc::new(n));
+                                        model.insert(key, n);
+                                    }
+                                }
+                            }
+                            map.write(batch);
+                        }
+                        8 => {
+                            let keys: Vec<u32> = (0..3).map(|offset| (key + offset) % 24).collect();
+                            map.remove_many(keys.clone());
+                            for key in keys {
+                                model.remove(&key);
+                            }
+                        }
+                        9 => {
+                            let pairs: Vec<(u32, u32)> =
+                                (0..3).map(|offset| ((key + offset) % 24, n)).collect();
+                            map.insert_many(
+                                pairs.iter().map(|&(key, value)| (key, Arc::new(value))),
+                            );
+                            model.extend(pairs);
+                        }
+                        10 | 11 => {
+                            assert_eq!(map.set(key, Arc::new(n)), model.insert(key, n).is_some())
+                        }
+                        _ => assert_eq!(
+                            map.replace(key, Arc::new(n))
+                                .map(|value| *value.into_owned()),
+                            model.insert(key, n)
+                        ),
+                    }
+                }
+                op => model.apply(&mut map, op),
+            }
+            check(&map, &model);
+        }
+    }
+
+    // A key updated since the checkpoint and then removed stays removed
+    let mut map: IndexedRollbackMap<u32, u32> = IndexedRollbackMap::new();
+    map.insert(1, 1);
+    let checkpoint = map.checkpoint().unwrap();
+    map.insert(1, 2);
+    assert_eq!(map.remove(&1), Some(2));
+    assert_eq!(map.get(&1), None);
+    assert_eq!(map.len(), 0);
+    map.debug_assert_consistency();
+    assert!(map.rollback(checkpoint));
+    assert_eq!(map.get(&1), Some(&1));
+
+    let path = std::env::temp_dir().join(format!("rollbackmap-test-len-{}", std::process::id()));
+    let mut map = SpillingRollbackMap::new(&path, 1, LittleEndian, LengthPrefixed).unwrap();
+    map.insert(1u32, "a".to_owned()).unwrap();
+    map.insert(2, "b".to_owned()).unwrap();
+    map.checkpoint().unwrap();
+    map.checkpoint().unwrap();
+    map.checkpoint().unwrap();
+    assert!(map.spilled_checkpoints_count() > 0);
+    map.insert(1, "c".to_owned()).unwrap();
+    map.insert(2, "d".to_owned()).unwrap();
+    assert_eq!(map.remove(&1).unwrap(), Some("c".to_owned()));
+    assert_eq!(map.get(&1).unwrap(), None);
+    assert_eq!(map.len(), 1);
+    let map = map.into_map().unwrap();
+    map.debug_assert_consistency();
+    assert_eq!(map.get(&1), None);
+    assert_eq!(map.len(), 1);
+}
+
+#[test]
+fn test_validate() {
+    let mut map: RollbackMap<u32, &str> = RollbackMap::new();
+    map.insert(1, "a");
+    map.insert(2, "b");
+    map.checkpoint();
+    map.insert(3, "c");
+    map.remove(&1);
+    assert_eq!(map.validate(), Ok(()));
+
+    // Folding a layer that removes a key added by the older one leaves no tombstone
+    map.checkpoint();
+    map.remove(&3);
+    map.checkpoint();
+    let pin = map.pin(0).unwrap();
+    map.prune();
+    assert_eq!(map.validate(), Ok(()));
+    drop(pin);
+
+    let mut broken = map.clone();
+    broken.versions.last_mut().unwrap().removed_keys.insert(2);
+    broken.versions.last_mut().unwrap().data.insert(2, "d");
+    assert_eq!(
+        broken.validate(),
+        Err(Inconsistency::RemovedAndPresent { layer: 2 })
+    );
+
+    let mut broken = map.clone();
+    broken.versions.last_mut().unwrap().removed_keys.insert(7);
+    assert_eq!(
+        broken.validate(),
+        Err(Inconsistency::DanglingTombstone { layer: 2 })
+    );
+
+    let mut broken = map.clone();
+    broken.versions[1].checkpoint = 0;
+    assert_eq!(
+        broken.validate(),
+        Err(Inconsistency::CheckpointOrder { layer: 1 })
+    );
+
+    let mut broken = map.clone();
+    broken.versions.last_mut().unwrap().values_count += 1;
+    assert_eq!(
+        broken.validate(),
+        Err(Inconsistency::LengthMismatch {
+            layer: 2,
+            tracked: 2,
+            visible: 1
+        })
+    );
+
+    let mut broken = map.clone();
+    broken.clear();
+    broken.versions.last_mut().unwrap().removed_keys.insert(2);
+    assert_eq!(
+        broken.validate(),
+        Err(Inconsistency::ClearedTombstone { layer: 2 })
+    );
+    assert_eq!(
+        Inconsistency::ClearedTombstone { layer: 2 }.to_string(),
+        "cleared layer 2 removes keys below it"
+    );
+    if cfg!(debug_assertions) {
+        let panic = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            broken.debug_assert_consistency()
+        }))
+        .unwrap_err();
+        assert_eq!(
+            panic.downcast_ref::<String>().unwrap(),
+            "inconsistent RollbackMap: cleared layer 2 removes keys below it"
+        );
+    }
+
+    // Nothing below a cleared layer is counted
+    map.clear();
+    map.insert(4, "d");
+    assert_eq!(map.validate(), Ok(()));
+    assert_eq!(map.len(), 1);
 }
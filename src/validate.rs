@@ -0,0 +1,193 @@
+//! Consistency checks of the layers of a [`RollbackMap`].
+//!
+//! [`RollbackMap::validate`] verifies the invariants every mutation has to keep:
+//! no key is both inserted and removed in a layer, the removed keys are visible
+//! in the older layers, the checkpoints are increasing, the tracked lengths match
+//! the merged view, and a cleared layer does not reach the layers below it.
+//!
+//! With the `debug-checks` feature enabled, the map is validated after every mutation,
+//! and an inconsistency panics right where it is introduced. It is meant for tests
+//! and fuzzing, as every check merges all the layers.
+use crate::rollbackmap::{RollbackMap, VersionState};
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fmt;
+
+/// Error returned by [`RollbackMap::validate`], naming the first inconsistent layer.
+///
+/// The layers are indexed from the oldest one, the last one holds the changes
+/// since the last checkpoint.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Inconsistency {
+    /// A key is both inserted and removed in the layer.
+    RemovedAndPresent {
+        /// Index of the layer.
+        layer: usize,
+    },
+    /// A key removed in the layer is not visible in the older layers.
+    DanglingTombstone {
+        /// Index of the layer.
+        layer: usize,
+    },
+    /// The checkpoint of the layer is not greater than the one of the older layer.
+    CheckpointOrder {
+        /// Index of the layer.
+        layer: usize,
+    },
+    /// The length tracked by the layer differs from the count of its visible values.
+    LengthMismatch {
+        /// Index of the layer.
+        layer: usize,
+        /// Length tracked by the layer.
+        tracked: usize,
+        /// Count of the values visible in the layer.
+        visible: usize,
+    },
+    /// A cleared layer removes keys, although the older layers are not visible to it.
+    ClearedTombstone {
+        /// Index of the layer.
+        layer: usize,
+    },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Inconsistency::RemovedAndPresent { layer } => {
+                write!(f, "layer {} both inserts and removes a key", layer)
+            }
+            Inconsistency::DanglingTombstone { layer } => {
+                write!(f, "layer {} removes a key missing below it", layer)
+            }
+            Inconsistency::CheckpointOrder { layer } => {
+                write!(f, "layer {} does not follow the checkpoint below it", layer)
+            }
+            Inconsistency::LengthMismatch {
+                layer,
+                tracked,
+                visible,
+            } => write!(
+                f,
+                "layer {} tracks length {}, but has {} values",
+                layer, tracked, visible
+            ),
+            Inconsistency::ClearedTombstone { layer } => {
+                write!(f, "cleared layer {} removes keys below it", layer)
+            }
+        }
+    }
+}
+
+impl Error for Inconsistency {}
+
+impl<K: Ord, V> RollbackMap<K, V> {
+    /// Checks the invariants of the map layers.
+    ///
+    /// Returns the first inconsistency found, from the oldest layer.
+    /// The check merges all the layers, so it takes time proportional to the size
+    /// of the whole history.
+    ///
+    /// # Examples
+    ///
+    /// Basic usage:
+    ///
+    /// ```
+    /// use rollbackmap::RollbackMap;
+    ///
+    /// let mut map = RollbackMap::new();
+    /// map.insert(1, "a");
+    /// map.checkpoint();
+    /// map.remove(&1);
+    /// map.insert(2, "b");
+    ///
+    /// assert_eq!(map.validate(), Ok(()));
+    /// ```
+    pub fn validate(&self) -> Result<(), Inconsistency> {
+        let (last, older) = self
+            .versions
+            .split_last()
+            .expect("the current version is always present");
+        validate_layers(older, last, self.partial)
+    }
+
+    // Validates the map after a mutation if the `debug-checks` feature is enabled.
+    pub(crate) fn debug_check(&self) {
+        if cfg!(feature = "debug-checks") {
+            self.assert_valid();
+        }
+    }
+
+    // Panics with the inconsistency found by RollbackMap::validate.
+    pub(crate) fn assert_valid(&self) {
+        if let Err(inconsistency) = self.validate() {
+            panic!("inconsistent RollbackMap: {}", inconsistency);
+        }
+    }
+}
+
+// Same as RollbackMap::debug_check, the current version is given apart from
+// the older ones, so it can be checked while they are borrowed.
+#[cfg(feature = "debug-checks")]
+pub(crate) fn debug_check<K: Ord, V>(
+    older: &[VersionState<K, V>],
+    last: &VersionState<K, V>,
+    partial: bool,
+) {
+    if let Err(inconsistency) = validate_layers(older, last, partial) {
+        panic!("inconsistent RollbackMap: {}", inconsistency);
+    }
+}
+
+#[cfg(not(feature = "debug-checks"))]
+#[inline(always)]
+pub(crate) fn debug_check<K: Ord, V>(
+    _older: &[VersionState<K, V>],
+    _last: &VersionState<K, V>,
+    _partial: bool,
+) {
+}
+
+// Merges the layers from the oldest one, checking every layer against the view below it.
+// If the map is partial, the keys below its oldest layer are unknown,
+// so the merged view is only checked from the first cleared layer.
+fn validate_layers<K: Ord, V>(
+    older: &[VersionState<K, V>],
+    last: &VersionState<K, V>,
+    partial: bool,
+) -> Result<(), Inconsistency> {
+    let mut visible: BTreeSet<&K> = BTreeSet::new();
+    let mut complete = !partial;
+    let mut previous = 0;
+    for (layer, version) in older.iter().chain(Some(last)).enumerate() {
+        if layer > 0 && version.checkpoint <= previous {
+            return Err(Inconsistency::CheckpointOrder { layer });
+        }
+        previous = version.checkpoint;
+
+        if version.detached {
+            if !version.removed_keys.is_empty() {
+                return Err(Inconsistency::ClearedTombstone { layer });
+            }
+            visible.clear();
+            complete = true;
+        }
+        for key in &version.removed_keys {
+            if version.data.contains_key(key) {
+                return Err(Inconsistency::RemovedAndPresent { layer });
+            }
+            if !visible.remove(key) && complete {
+                return Err(Inconsistency::DanglingTombstone { layer });
+            }
+        }
+        visible.extend(version.data.keys());
+
+        if complete && version.values_count != visible.len() {
+            return Err(Inconsistency::LengthMismatch {
+                layer,
+                tracked: version.values_count,
+                visible: visible.len(),
+            });
+        }
+    }
+    Ok(())
+}